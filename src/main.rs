@@ -1,31 +1,66 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Write, BufRead};
-use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::io::Read;
+use std::io::{self, BufRead, IsTerminal, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 
 use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
-use rustyline::highlight::Highlighter;
+use rustyline::highlight::{CmdKind, Highlighter};
 use rustyline::hint::Hinter;
-use rustyline::history::DefaultHistory;
+use rustyline::history::{DefaultHistory, History, SearchDirection};
 use rustyline::validate::Validator;
-use rustyline::{ColorMode, CompletionType, Config, Context, EditMode, Editor, Helper};
+use rustyline::{
+    Cmd, ColorMode, CompletionType, Config, Context, EditMode, Editor, Helper, KeyCode, KeyEvent,
+    Modifiers,
+};
 
-use rig::completion::Prompt;
-use rig::providers::openai;
+use futures::StreamExt;
+use rig::agent::Agent;
+use rig::completion::{CompletionError, Message};
+use rig::providers::{anthropic, ollama, openai};
+use rig::streaming::{StreamingChat, StreamingChoice, StreamingResult};
 
 // --- Constants and Type Definitions ---
-const BUILTINS: [&str; 5] = ["echo", "exit", "type", "pwd", "history"];
+const BUILTINS: [&str; 27] = [
+    "echo", "exit", "cd", "type", "which", "pwd", "history", "shopt", "export", "unset", "source",
+    ".", "alias", "unalias", "jobs", "fg", "pushd", "popd", "dirs", "test", "[", "printf",
+    "rehash", "hash", "set", "umask", "trap",
+];
 
-// Tab completion candidates (only echo and exit)
-const COMPLETION_COMMANDS: [&str; 2] = ["echo", "exit"];
+/// Number of history entries kept when `HISTSIZE` isn't set
+const DEFAULT_HISTSIZE: usize = 1000;
 
 /// Command completer
 struct CommandCompleter {
-    executables: HashMap<String, PathBuf>,
+    executables: ExecutableCache,
+    /// Names of the shell's variables, refreshed before each prompt
+    variables: Vec<String>,
+    /// Names of the shell's aliases, refreshed before each prompt
+    aliases: Vec<String>,
+    /// The shell's `$HOME`, refreshed before each prompt, for completing `~/`
+    home: Option<String>,
+    /// Text shown right-aligned on the prompt line (`RPROMPT`), while reading
+    /// a command
+    right_prompt: Option<String>,
+    /// Width of the last line of the main prompt, for placing `right_prompt`
+    prompt_width: usize,
+    /// How TAB shows multiple completions
+    completion_type: CompletionType,
+    /// Where the completions TAB is cycling through start, and their text
+    /// (circular completion only), to highlight the one on the line
+    cycling: RefCell<Option<(usize, Vec<String>)>>,
 }
 
 impl Completer for CommandCompleter {
@@ -37,24 +72,47 @@ impl Completer for CommandCompleter {
         pos: usize,
         _ctx: &Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        // Only complete at command start (no spaces or only leading spaces)
-        let trimmed = line[..pos].trim_start();
-
-        // If contains space, already entering arguments, don't complete
-        if trimmed.contains(' ') {
-            return Ok((pos, vec![]));
+        let (start, candidates) = self.candidates(line, pos);
+        if self.completion_type == CompletionType::Circular && candidates.len() > 1 {
+            let replacements = candidates.iter().map(|c| c.replacement.clone()).collect();
+            *self.cycling.borrow_mut() = Some((start, replacements));
         }
+        Ok((start, candidates))
+    }
+}
+
+impl CommandCompleter {
+    /// Completions for the word before `pos`, and where that word starts;
+    /// they're sorted, so cycling through them is predictable
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<Pair>) {
+        let trimmed = line[..pos].trim_start();
 
         // Get the currently typed word
         let word = &line[..pos];
         let start = word.rfind(|c: char| c.is_whitespace()).map_or(0, |i| i + 1);
         let prefix = &word[start..];
 
+        // A `$NAME` or `${NAME` being typed anywhere completes a variable name
+        if let Some(candidates) = self.complete_variable(prefix) {
+            return (start, candidates);
+        }
+
+        // If contains space, already entering arguments: complete file paths
+        // (only directories for commands that change directory)
+        if trimmed.contains(' ') {
+            let command = trimmed.split_whitespace().next().unwrap_or("");
+            let dirs_only = matches!(command, "cd" | "pushd");
+            return (
+                start,
+                complete_path(prefix, dirs_only, self.home.as_deref()),
+            );
+        }
+
         // Find all matching completion candidates
         let mut candidates: Vec<Pair> = Vec::new();
 
-        // 1. Add matching builtin commands (echo and exit)
-        for cmd in &COMPLETION_COMMANDS {
+        // 1. Add matching builtin commands
+        for cmd in &BUILTINS {
             if cmd.starts_with(prefix) {
                 candidates.push(Pair {
                     display: cmd.to_string(),
@@ -64,83 +122,889 @@ impl Completer for CommandCompleter {
         }
 
         // 2. Add matching external executable files
-        for executable_name in self.executables.keys() {
-            if executable_name.starts_with(prefix) {
-                candidates.push(Pair {
-                    display: executable_name.clone(),
-                    replacement: format!("{} ", executable_name), // Add trailing space
-                });
-            }
+        for executable_name in self.executables.names_starting_with(prefix) {
+            candidates.push(Pair {
+                replacement: format!("{} ", executable_name), // Add trailing space
+                display: executable_name,
+            });
         }
 
-        // Sort alphabetically
+        // Sort alphabetically, dropping executables that share a builtin's name
         candidates.sort_by(|a, b| a.display.cmp(&b.display));
+        candidates.dedup_by(|a, b| a.display == b.display);
 
-        Ok((start, candidates))
+        (start, candidates)
+    }
+}
+
+/// Complete a partially typed path against the filesystem
+///
+/// The directory part of `prefix` (which may start with `~/`, for `home`) is
+/// kept as typed; directories get a trailing `/` and files a trailing space.
+/// With `dirs_only`, files aren't offered.
+fn complete_path(prefix: &str, dirs_only: bool, home: Option<&str>) -> Vec<Pair> {
+    let (dir_part, name_part) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+
+    // Directory to list, with `~/` expanded for the lookup only
+    let dir = if dir_part.is_empty() {
+        PathBuf::from(".")
+    } else if let Some(rest) = dir_part.strip_prefix("~/") {
+        PathBuf::from(home.unwrap_or_default()).join(rest)
+    } else {
+        PathBuf::from(dir_part)
+    };
+
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<Pair> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            // Hidden entries only when explicitly asked for
+            if !name.starts_with(name_part)
+                || (name.starts_with('.') && !name_part.starts_with('.'))
+            {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            if dirs_only && !is_dir {
+                return None;
+            }
+            let suffix = if is_dir { "/" } else { " " };
+            Some(Pair {
+                display: format!("{}{}", name, if is_dir { "/" } else { "" }),
+                replacement: format!("{}{}{}", dir_part, name, suffix),
+            })
+        })
+        .collect();
+
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
+
+impl CommandCompleter {
+    /// The span of the completion TAB is cycling through, if one of them is
+    /// on the line and the cursor is right after it (its trailing space left
+    /// out)
+    fn cycled_completion(&self, line: &str, pos: usize) -> Option<(usize, usize)> {
+        let cycling = self.cycling.borrow();
+        let (start, replacements) = cycling.as_ref()?;
+        let replacement = replacements
+            .iter()
+            .find(|r| start + r.len() == pos && line.get(*start..pos) == Some(r.as_str()))?;
+        Some((*start, start + replacement.trim_end().len()))
+    }
+
+    /// Column (1-based) where the right prompt starts, if there is one and it
+    /// fits beside the main prompt in the terminal's current width
+    fn right_prompt_column(&self) -> Option<usize> {
+        let right_width = display_width(self.right_prompt.as_ref()?);
+        let columns = terminal_width()?;
+        (self.prompt_width + right_width < columns).then(|| columns - right_width + 1)
+    }
+
+    /// Color the command word green if it names a builtin, alias or
+    /// executable, and red otherwise
+    fn highlight_command<'l>(&self, line: &'l str) -> Cow<'l, str> {
+        // AI requests are plain language, not commands
+        let start = line.len() - line.trim_start().len();
+        if line[start..].starts_with('!') {
+            return Cow::Borrowed(line);
+        }
+        let end = line[start..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '|' | ';' | '&' | '<' | '>'))
+            .map_or(line.len(), |i| start + i);
+        let command = &line[start..end];
+        if command.is_empty() {
+            return Cow::Borrowed(line);
+        }
+
+        let known = BUILTINS.contains(&command)
+            || self.aliases.iter().any(|alias| alias == command)
+            || self.executables.contains(command)
+            || (command.contains('/') && is_executable(Path::new(command)));
+        let color = if known { "\x1b[32m" } else { "\x1b[31m" };
+        Cow::Owned(format!(
+            "{}{}{}\x1b[0m{}",
+            &line[..start],
+            color,
+            command,
+            &line[end..]
+        ))
+    }
+
+    /// Complete a variable name after the last `$` (or `${`) in `prefix`,
+    /// keeping the text before it; `None` if `prefix` doesn't end in one
+    fn complete_variable(&self, prefix: &str) -> Option<Vec<Pair>> {
+        let dollar = prefix.rfind('$')?;
+        let after = &prefix[dollar + 1..];
+        let (name_part, braced) = match after.strip_prefix('{') {
+            Some(name_part) => (name_part, true),
+            None => (after, false),
+        };
+        if !name_part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return None;
+        }
+
+        let mut candidates: Vec<Pair> = self
+            .variables
+            .iter()
+            .filter(|name| name.starts_with(name_part))
+            .map(|name| Pair {
+                display: name.clone(),
+                // No trailing space, so a path can follow (e.g. `$HOME/`)
+                replacement: if braced {
+                    format!("{}{{{}}}", &prefix[..=dollar], name)
+                } else {
+                    format!("{}{}", &prefix[..=dollar], name)
+                },
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+        Some(candidates)
     }
 }
 
+/// Suggest the rest of the most recent history entry starting with the line,
+/// accepted with the right arrow
 impl Hinter for CommandCompleter {
     type Hint = String;
 
-    fn hint(&self, _line: &str, _pos: usize, _ctx: &Context<'_>) -> Option<Self::Hint> {
-        None
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<Self::Hint> {
+        // Only while typing at the end of a non-empty line
+        if line.trim().is_empty() || pos < line.len() {
+            return None;
+        }
+
+        // Search newest first, skipping entries identical to the line
+        let history = ctx.history();
+        let mut index = history.len().checked_sub(1)?;
+        loop {
+            let found = history
+                .starts_with(line, index, SearchDirection::Reverse)
+                .ok()??;
+            if found.entry.len() > line.len() {
+                return Some(found.entry[line.len()..].to_string());
+            }
+            index = found.idx.checked_sub(1)?;
+        }
     }
 }
 
-impl Highlighter for CommandCompleter {}
+/// Color the command word, and draw the right prompt
+impl Highlighter for CommandCompleter {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let highlighted = match self.cycled_completion(line, pos) {
+            // Show the completion TAB put on the line in reverse video
+            Some((start, end)) => Cow::Owned(format!(
+                "{}\x1b[7m{}\x1b[0m{}",
+                self.highlight_command(&line[..start]),
+                &line[start..end],
+                &line[end..]
+            )),
+            None => self.highlight_command(line),
+        };
+
+        // Once the line reaches the right prompt, clear what's left of it
+        match self.right_prompt_column() {
+            Some(column) if self.prompt_width + display_width(line) + 1 >= column => {
+                Cow::Owned(format!("{}\x1b[K", highlighted))
+            }
+            _ => highlighted,
+        }
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        let (Some(right), Some(column)) = (&self.right_prompt, self.right_prompt_column()) else {
+            return Cow::Borrowed(prompt);
+        };
+
+        // Draw the right prompt first, then return to the start of the line
+        // for the main prompt; escapes take no width, so the line editor's
+        // layout is unchanged
+        let last_line = prompt.rfind('\n').map_or(0, |i| i + 1);
+        Cow::Owned(format!(
+            "{}\x1b[{}G{}\r{}",
+            &prompt[..last_line],
+            column,
+            right,
+            &prompt[last_line..]
+        ))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        // Dim, so a suggestion doesn't look like typed text
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        // The final refresh of an accepted line ends any completion cycle
+        if kind == CmdKind::ForcedRefresh {
+            *self.cycling.borrow_mut() = None;
+        }
+        // Recolor after every edit, not just on a final refresh
+        kind != CmdKind::MoveCursor
+    }
+}
 
 impl Validator for CommandCompleter {}
 
 impl Helper for CommandCompleter {}
 
+/// A redirection of standard output or standard error
+#[derive(Debug, Clone)]
+enum OutputRedirect {
+    /// `n>file`, `n>>file` or `n>|file`: descriptor, file path and how to open it
+    File(i32, String, WriteMode),
+    /// `n>&m`: make descriptor n a copy of descriptor m
+    Dup(i32, i32),
+}
+
+/// How an output redirection opens its file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WriteMode {
+    /// `>`: truncate, unless `noclobber` protects an existing file
+    Truncate,
+    /// `>>`: append, creating the file if needed
+    Append,
+    /// `>|`: truncate even under `noclobber`
+    Clobber,
+}
+
 /// Output redirection information
 #[derive(Debug, Clone)]
 struct Redirection {
-    /// Standard output redirect file path
-    stdout_file: Option<String>,
-    /// Whether standard output is in append mode (true=>>, false=>)
-    stdout_append: bool,
-    /// Standard error redirect file path
-    stderr_file: Option<String>,
-    /// Whether standard error is in append mode (true=2>>, false=2>)
-    stderr_append: bool,
+    /// Redirections of stdout and stderr, applied left to right like bash,
+    /// so `>file 2>&1` and `2>&1 >file` differ
+    outputs: Vec<OutputRedirect>,
+    /// Standard input redirect file path
+    stdin_file: Option<String>,
+    /// Here-document body fed to standard input (`<<DELIM`)
+    heredoc: Option<String>,
+    /// Whether the here-document body is expanded (false if the delimiter was quoted)
+    heredoc_expand: bool,
+    /// Here-string word (`<<<word`) as typed; `parse_command` expands it into `heredoc`
+    here_string: Option<String>,
+}
+
+/// Options toggled with the `shopt` builtin
+#[derive(Debug, Clone, Default)]
+struct ShellOptions {
+    /// Ask before `>` truncates an existing file
+    confirm_overwrite: bool,
+    /// Page long builtin output through `$PAGER`
+    pager: bool,
+    /// `set -e`: stop running input once a command fails
+    errexit: bool,
+    /// `set -o pipefail`: a pipeline fails if any stage fails
+    pipefail: bool,
+    /// `set -o noclobber`: `>` refuses to overwrite an existing file (`>|` still does)
+    noclobber: bool,
+}
+
+/// Names accepted by `shopt`, in listing order
+const SHOPT_NAMES: [&str; 2] = ["confirm_overwrite", "pager"];
+
+impl ShellOptions {
+    /// Get a mutable reference to the option with the given `shopt` name
+    fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "confirm_overwrite" => Some(&mut self.confirm_overwrite),
+            "pager" => Some(&mut self.pager),
+            _ => None,
+        }
+    }
+
+    /// Get a mutable reference to the option with the given `set -o` name
+    fn set_option_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "noclobber" => Some(&mut self.noclobber),
+            "pipefail" => Some(&mut self.pipefail),
+            _ => None,
+        }
+    }
+}
+
+/// Names accepted by `set -o`, in listing order
+const SET_OPTION_NAMES: [&str; 3] = ["errexit", "noclobber", "pipefail"];
+
+/// Settings read from `~/.config/shell-ai/config.toml`
+///
+/// Every field is optional; environment variables override file values.
+#[derive(Debug, Clone, Default)]
+struct ShellConfig {
+    /// Prompt template, used as `PS1`
+    prompt: Option<String>,
+    /// Show the current directory in the default prompt, used as `ENABLE_CUR_DIR_DISPLAY`
+    show_cur_dir: Option<bool>,
+    /// In-memory history size, used as `HISTSIZE`
+    history_size: Option<usize>,
+    /// AI service, used as `SHELL_AI_PROVIDER`
+    ai_provider: Option<String>,
+    /// AI model, used as `SHELL_AI_MODEL`
+    ai_model: Option<String>,
+    /// Comma-separated dangerous command patterns, used as `SHELL_AI_DANGER_PATTERNS`
+    ai_danger_patterns: Option<String>,
+    /// Line editing key bindings
+    edit_mode: Option<EditMode>,
+    /// How TAB shows multiple completions
+    completion_type: Option<CompletionType>,
+}
+
+impl ShellConfig {
+    /// Environment variables backed by config values, paired with the value
+    fn env_defaults(&self) -> Vec<(&'static str, String)> {
+        let mut vars = Vec::new();
+        if let Some(prompt) = &self.prompt {
+            vars.push(("PS1", prompt.clone()));
+        }
+        if let Some(show) = self.show_cur_dir {
+            vars.push(("ENABLE_CUR_DIR_DISPLAY", show.to_string()));
+        }
+        if let Some(size) = self.history_size {
+            vars.push(("HISTSIZE", size.to_string()));
+        }
+        if let Some(provider) = &self.ai_provider {
+            vars.push(("SHELL_AI_PROVIDER", provider.clone()));
+        }
+        if let Some(model) = &self.ai_model {
+            vars.push(("SHELL_AI_MODEL", model.clone()));
+        }
+        if let Some(patterns) = &self.ai_danger_patterns {
+            vars.push(("SHELL_AI_DANGER_PATTERNS", patterns.clone()));
+        }
+        vars
+    }
+
+    /// Set one `key = value` entry, checking the value's type and range
+    fn set(&mut self, key: &str, value: ConfigValue) -> Result<(), String> {
+        match (key, value) {
+            ("prompt", ConfigValue::String(s)) => self.prompt = Some(s),
+            ("show_cur_dir", ConfigValue::Boolean(b)) => self.show_cur_dir = Some(b),
+            ("history_size", ConfigValue::Integer(n)) => {
+                let size = usize::try_from(n).map_err(|_| "history_size must not be negative")?;
+                self.history_size = Some(size);
+            }
+            ("ai_provider", ConfigValue::String(s)) => match s.as_str() {
+                "openai" | "anthropic" | "ollama" => self.ai_provider = Some(s),
+                _ => {
+                    return Err(format!(
+                        "unknown ai_provider `{}` (expected openai, anthropic or ollama)",
+                        s
+                    ));
+                }
+            },
+            ("ai_model", ConfigValue::String(s)) => self.ai_model = Some(s),
+            ("ai_danger_patterns", ConfigValue::String(s)) => self.ai_danger_patterns = Some(s),
+            ("edit_mode", ConfigValue::String(s)) => match s.as_str() {
+                "emacs" => self.edit_mode = Some(EditMode::Emacs),
+                "vi" => self.edit_mode = Some(EditMode::Vi),
+                _ => return Err(format!("unknown edit_mode `{}` (expected emacs or vi)", s)),
+            },
+            ("completion_type", ConfigValue::String(s)) => match s.as_str() {
+                "list" => self.completion_type = Some(CompletionType::List),
+                "circular" => self.completion_type = Some(CompletionType::Circular),
+                _ => {
+                    return Err(format!(
+                        "unknown completion_type `{}` (expected list or circular)",
+                        s
+                    ));
+                }
+            },
+            ("history_size", _) => return Err("history_size must be an integer".to_string()),
+            ("show_cur_dir", _) => return Err("show_cur_dir must be true or false".to_string()),
+            (
+                "prompt" | "ai_provider" | "ai_model" | "ai_danger_patterns" | "edit_mode"
+                | "completion_type",
+                _,
+            ) => {
+                return Err(format!("{} must be a string", key));
+            }
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Key bindings matching the user's editor (`$VISUAL`, else `$EDITOR`): vi
+/// for vi and its clones, emacs otherwise
+fn editor_edit_mode() -> EditMode {
+    let editor = env::var("VISUAL")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .or_else(|| env::var("EDITOR").ok())
+        .unwrap_or_default();
+    // The program name, without its directory or arguments
+    let program = editor.split_whitespace().next().unwrap_or("");
+    let name = Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    match name {
+        "vi" | "vim" | "nvim" | "gvim" | "view" | "nvi" | "elvis" | "vile" => EditMode::Vi,
+        _ => EditMode::Emacs,
+    }
+}
+
+/// A value on the right of `=` in the config file
+enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+/// Load the config file, reporting invalid entries and skipping them
+///
+/// The file is `$XDG_CONFIG_HOME/shell-ai/config.toml`, falling back to
+/// `~/.config/shell-ai/config.toml`; a missing file gives the defaults.
+fn load_config() -> ShellConfig {
+    let dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var("HOME") {
+            Ok(home) => Path::new(&home).join(".config"),
+            Err(_) => return ShellConfig::default(),
+        },
+    };
+    let path = dir.join("shell-ai").join("config.toml");
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return ShellConfig::default(),
+        Err(e) => {
+            eprintln!("shell-ai: {}", path_error(&path.display().to_string(), e));
+            return ShellConfig::default();
+        }
+    };
+
+    let mut config = ShellConfig::default();
+    for (number, line) in content.lines().enumerate() {
+        let result = parse_config_line(line).and_then(|entry| match entry {
+            Some((key, value)) => config.set(&key, value),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("shell-ai: {}:{}: {}", path.display(), number + 1, e);
+        }
+    }
+    config
+}
+
+/// Parse one line of the config file into a key and value
+///
+/// Supports the flat subset of TOML the settings need: `key = value` with
+/// string, integer or boolean values, blank lines and `#` comments.
+fn parse_config_line(line: &str) -> Result<Option<(String, ConfigValue)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    if line.starts_with('[') {
+        return Err("tables are not supported".to_string());
+    }
+    let (key, rest) = line
+        .split_once('=')
+        .ok_or_else(|| "expected `key = value`".to_string())?;
+    let key = key.trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!("invalid key `{}`", key));
+    }
+
+    let rest = rest.trim_start();
+    let (value, trailing) = if let Some(body) = rest.strip_prefix('"') {
+        // Basic string, with backslash escapes
+        let mut value = String::new();
+        let mut chars = body.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i + 1,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, c)) => return Err(format!("invalid escape `\\{}`", c)),
+                    None => return Err("unterminated string".to_string()),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        };
+        (ConfigValue::String(value), &body[end..])
+    } else if let Some(body) = rest.strip_prefix('\'') {
+        // Literal string, taken as is
+        let end = body
+            .find('\'')
+            .ok_or_else(|| "unterminated string".to_string())?;
+        (
+            ConfigValue::String(body[..end].to_string()),
+            &body[end + 1..],
+        )
+    } else {
+        let end = rest.find('#').unwrap_or(rest.len());
+        let word = rest[..end].trim();
+        let value = match word {
+            "true" => ConfigValue::Boolean(true),
+            "false" => ConfigValue::Boolean(false),
+            _ => word
+                .replace('_', "")
+                .parse()
+                .map(ConfigValue::Integer)
+                .map_err(|_| format!("invalid value `{}`", word))?,
+        };
+        (value, &rest[end..])
+    };
+
+    let trailing = trailing.trim_start();
+    if !trailing.is_empty() && !trailing.starts_with('#') {
+        return Err(format!("unexpected `{}` after value", trailing));
+    }
+    Ok(Some((key.to_string(), value)))
+}
+
+/// State carried across commands for the lifetime of the shell
+struct ShellState {
+    /// Line editor, which also owns the command history
+    editor: Editor<CommandCompleter, DefaultHistory>,
+    /// Executables found in PATH, shared with the completer
+    executables: ExecutableCache,
+    /// Number of history entries at last file write
+    last_written_count: usize,
+    /// When each history entry was run (Unix time), parallel to the history
+    history_times: Vec<i64>,
+    /// Text to pre-fill the next prompt with (an AI suggestion to edit)
+    pending_input: Option<String>,
+    /// Runtime and agents reused across AI requests
+    ai: AiSession,
+    /// Requests and replies of the AI suggestion being refined
+    ai_conversation: Vec<(String, String)>,
+    /// Options toggled with `shopt`
+    options: ShellOptions,
+    /// Whether a preexec/precmd hook is currently running
+    in_hook: bool,
+    /// Whether stdin is a terminal (false in batch/scripted use)
+    interactive: bool,
+    /// Whether stdin is the script the shell is reading, which commands
+    /// mustn't consume
+    stdin_is_script: bool,
+    /// Whether this is a forked subshell running a `$(...)`, `<(...)` or `>(...)`
+    subshell: bool,
+    /// Exported variables, passed to every command the shell runs
+    env: HashMap<String, String>,
+    /// Exit status of the last command, exposed as `$?`
+    last_status: i32,
+    /// The shell's PID, exposed as `$$` (unchanged in subshells, like bash)
+    shell_pid: u32,
+    /// PID of the most recent background job, exposed as `$!`
+    last_background_pid: Option<u32>,
+    /// Here-document bodies read for the current line, in order of their `<<`
+    heredocs: VecDeque<String>,
+    /// Aliases defined with `alias`, by name
+    aliases: HashMap<String, String>,
+    /// Background jobs started with `&`, oldest first
+    jobs: Vec<Job>,
+    /// Directories saved by `pushd`, most recent last
+    dir_stack: Vec<PathBuf>,
+    /// Set when an expansion fails, so the command being expanded doesn't run
+    expansion_error: bool,
+    /// Set when `set -e` stops on a failed command, so callers stop reading input
+    errexit_abort: bool,
+    /// Shell ends (fd) and subshell PIDs of the current command's `<(...)` and `>(...)`
+    #[cfg(unix)]
+    process_substitutions: Vec<(i32, libc::pid_t)>,
+    /// Commands set with `trap`, by signal number (0 for `EXIT`)
+    traps: HashMap<i32, String>,
+}
+
+/// A command running in the background
+struct Job {
+    /// Job number shown as `[n]`
+    id: usize,
+    /// The running process
+    child: std::process::Child,
+    /// Command line as typed, without the trailing `&`
+    command: String,
+}
+
+impl ShellState {
+    /// Look up a variable in the shell's environment
+    fn var(&self, name: &str) -> Option<&str> {
+        self.env.get(name).map(|s| s.as_str())
+    }
+
+    /// Snapshot of the command history, oldest first
+    fn history(&self) -> Vec<String> {
+        self.editor
+            .history()
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    /// History entries as `history` lists them: after their time, formatted
+    /// with `HISTTIMEFORMAT`, if it's set
+    fn listed_history(&self) -> Vec<String> {
+        let history = self.history();
+        match self.var("HISTTIMEFORMAT") {
+            Some(format) => history
+                .into_iter()
+                .zip(&self.history_times)
+                .map(|(entry, &time)| format!("{}{}", format_time(format, time), entry))
+                .collect(),
+            None => history,
+        }
+    }
+
+    /// Whether `HISTCONTROL` (a `:`-separated list) contains `setting`
+    fn history_control(&self, setting: &str) -> bool {
+        self.var("HISTCONTROL").is_some_and(|control| {
+            control
+                .split(':')
+                .any(|value| value == setting || value == "ignoreboth")
+        })
+    }
+
+    /// Whether `HISTCONTROL` keeps the command `line` (run as `entry`) out of
+    /// the history: `ignorespace` skips lines starting with a space, and
+    /// `ignoredups` repeats of the previous entry
+    fn history_ignores(&self, line: &str, entry: &str) -> bool {
+        if line.starts_with(' ') && self.history_control("ignorespace") {
+            return true;
+        }
+        self.history_control("ignoredups")
+            && self.editor.history().iter().next_back().map(String::as_str) == Some(entry)
+    }
+
+    /// Add a command run at `time` to the history
+    fn add_history(&mut self, entry: &str, time: i64) {
+        if self.editor.add_history_entry(entry).unwrap_or(false) {
+            self.history_times.push(time);
+        }
+        self.sync_history_times();
+    }
+
+    /// Remove the history entry at `index` along with its time
+    fn delete_history(&mut self, index: usize) {
+        // The history store can't remove a single entry, so rebuild it, adding
+        // each time back with its entry so the two stay in step
+        let mut entries = self.history_entries(0..self.editor.history().len());
+        entries.remove(index);
+        let _ = self.editor.clear_history();
+        self.history_times.clear();
+        for (entry, time) in entries {
+            self.add_history(&entry, time);
+        }
+        if index < self.last_written_count {
+            self.last_written_count -= 1;
+        }
+    }
+
+    /// Drop the times of entries the history dropped to stay within its size
+    fn sync_history_times(&mut self) {
+        let excess = self
+            .history_times
+            .len()
+            .saturating_sub(self.editor.history().len());
+        self.history_times.drain(..excess);
+        // The dropped entries came from the front, before any unwritten ones
+        self.last_written_count = self.last_written_count.saturating_sub(excess);
+    }
+
+    /// History entries in `range`, with when they ran
+    fn history_entries(&self, range: std::ops::Range<usize>) -> Vec<(String, i64)> {
+        self.editor
+            .history()
+            .iter()
+            .zip(&self.history_times)
+            .skip(range.start)
+            .take(range.len())
+            .map(|(entry, &time)| (entry.clone(), time))
+            .collect()
+    }
+}
+
+/// One command of a pipeline: command, arguments, redirections and the
+/// `NAME=value` variables set for it
+type PipelineStage = (
+    String,
+    Vec<String>,
+    Option<Redirection>,
+    Vec<(String, String)>,
+);
+
+/// Files opened for a command's redirections
+struct RedirectFiles {
+    stdin: Option<File>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+/// Which history entries a `history` command operates on
+#[derive(Debug, Clone, Copy)]
+enum HistorySelection {
+    /// Every entry
+    All,
+    /// The last n entries
+    Last(usize),
+    /// Entries start through end, 1-based and inclusive like the displayed numbers
+    Range(usize, usize),
+}
+
+impl HistorySelection {
+    /// Parse the numeric arguments of `history`: none, `N` (last N) or `START END`
+    fn from_args(args: &[String]) -> Self {
+        let numbers: Vec<usize> = args.iter().map_while(|s| s.parse().ok()).collect();
+        match numbers[..] {
+            [start, end, ..] => HistorySelection::Range(start, end),
+            [n] => HistorySelection::Last(n),
+            [] => HistorySelection::All,
+        }
+    }
+
+    /// Resolve to a half-open index range into a history of `len` entries,
+    /// clamping out-of-range endpoints
+    fn bounds(self, len: usize) -> (usize, usize) {
+        match self {
+            HistorySelection::All => (0, len),
+            HistorySelection::Last(n) => (len.saturating_sub(n), len),
+            HistorySelection::Range(start, end) => {
+                let end = end.min(len);
+                let start = start.saturating_sub(1).min(end);
+                (start, end)
+            }
+        }
+    }
+}
+
+/// How a command in an `&&`/`||`/`;` list depends on the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Connector {
+    /// First command of the list, or after `;`: always runs
+    Always,
+    /// After `&&`: runs only if the previous command succeeded
+    And,
+    /// After `||`: runs only if the previous command failed
+    Or,
 }
 
 /// Define all actions supported by the Shell
 enum CommandAction {
-    Exit,
+    /// Exit the shell with the given status, or the last command's status
+    Exit(Option<i32>),
     Echo(Vec<String>),
+    /// Print arguments according to a format string
+    Printf(Vec<String>),
     Type(Vec<String>),
+    /// Print the path each named command runs from
+    Which(Vec<String>),
     Pwd,
-    Ai(Vec<String>),
-    /// External command: contains executable file path and argument array
-    External(String, Vec<String>),
+    /// AI prompt words, and the pipeline (if any) the generated command feeds
+    Ai(Vec<String>, Option<String>),
+    /// Ask the AI to explain a command (`!?`), without running anything
+    AiExplain(Vec<String>),
+    /// External command: contains executable file path, argument array and
+    /// `NAME=value` assignments for its environment only
+    External(String, Vec<String>, Vec<(String, String)>),
+    /// External command started in the background with a trailing `&`
+    Background(String, Vec<String>, Vec<(String, String)>),
+    /// `NAME=value` words with no command: set shell variables
+    Assign(Vec<(String, String)>),
+    /// A builtin after `NAME=value` words: the variables are set while it runs
+    WithEnv(Vec<(String, String)>, Box<CommandAction>),
     /// Unknown command
     Unknown(String),
+    /// Nothing to run, e.g. because expanding the command failed; holds the exit status
+    Failed(i32),
     Cd(Vec<String>),
-    /// Pipeline command: contains array of multiple commands and their arguments
-    Pipeline(Vec<(String, Vec<String>)>),
-    /// History command: optional parameter specifies showing last n records
-    History(Option<usize>),
+    /// Push the current directory and change to another (or swap with the top)
+    Pushd(Option<String>),
+    /// Return to the directory on top of the stack and remove it
+    Popd,
+    /// Print the directory stack
+    Dirs,
+    /// Pipeline command: contains array of multiple commands, their arguments
+    /// and their own redirections
+    Pipeline(Vec<PipelineStage>),
+    /// History command: which records to show
+    History(HistorySelection),
     /// Read history from file
     HistoryRead(String),
-    /// Write history to file
-    HistoryWrite(String),
+    /// Write history (or a range of it) to file
+    HistoryWrite(String, HistorySelection),
     /// Append new history to file
     HistoryAppend(String),
+    /// Clear the in-memory history
+    HistoryClear,
+    /// Delete one history entry, by its displayed number
+    HistoryDelete(String),
+    /// Show or toggle shell options
+    Shopt(Vec<String>),
+    /// Show or toggle `set` options (`-e`, `-o pipefail`)
+    Set(Vec<String>),
+    /// Set or list exported variables
+    Export(Vec<String>),
+    /// Remove variables
+    Unset(Vec<String>),
+    /// Run the commands in a file in the current shell
+    Source(Vec<String>),
+    /// Define or list aliases
+    Alias(Vec<String>),
+    /// Remove aliases
+    Unalias(Vec<String>),
+    /// Evaluate a conditional expression: the command name (`test` or `[`) and its arguments
+    Test(Vec<String>),
+    /// Rebuild the cache of executables in PATH
+    Rehash,
+    /// Print the file creation mask, or set it from an octal number
+    Umask(Option<String>),
+    /// Set, reset or list commands run on signals
+    Trap(Vec<String>),
+    /// List background jobs
+    Jobs,
+    /// Bring a background job (by number, or the most recent) to the foreground
+    Fg(Option<usize>),
 }
 
 fn main() {
-    // Preload all executables at startup (Caching)
-    let all_executables = get_all_executables();
+    // Survive Ctrl-C while a command runs; the command itself still gets SIGINT
+    install_sigint_handler();
+
+    // Settings from the config file; environment variables take precedence
+    let shell_config = load_config();
+
+    // Find all executables in the background, so the prompt appears at once
+    let all_executables = ExecutableCache::scan_in_background(env::var("PATH").ok());
 
     // Configure rustyline Editor
+    let completion_type = shell_config.completion_type.unwrap_or(CompletionType::List);
     let config = Config::builder()
-        .completion_type(CompletionType::List) // List mode: first TAB rings bell, second TAB shows list
-        .edit_mode(EditMode::Emacs) // Emacs edit mode
+        .completion_type(completion_type) // List mode: first TAB rings bell, second TAB shows list; circular cycles
+        .edit_mode(shell_config.edit_mode.unwrap_or_else(editor_edit_mode)) // Emacs unless vi is configured or the editor
         .color_mode(ColorMode::Enabled) // Enable colors
-        .history_ignore_dups(false) // Don't deduplicate history commands
+        .history_ignore_dups(false) // `HISTCONTROL` decides what to deduplicate
+        .expect("Failed to configure history")
+        .max_history_size(history_limit(
+            env::var("HISTSIZE")
+                .ok()
+                .or(shell_config.history_size.map(|size| size.to_string()))
+                .as_deref(),
+        )) // Bound in-memory history
         .expect("Failed to configure history")
         .build();
 
@@ -148,55 +1012,184 @@ fn main() {
     let mut rl = Editor::with_config(config).expect("Failed to create editor");
     let completer = CommandCompleter {
         executables: all_executables.clone(),
+        variables: Vec::new(),
+        aliases: Vec::new(),
+        home: None,
+        right_prompt: None,
+        prompt_width: 0,
+        completion_type,
+        cycling: RefCell::new(None),
     };
     rl.set_helper(Some(completer));
 
+    // Ctrl-R / Ctrl-S: incremental reverse / forward search through history
+    rl.bind_sequence(KeyEvent::ctrl('R'), Cmd::ReverseSearchHistory);
+    rl.bind_sequence(KeyEvent::ctrl('S'), Cmd::ForwardSearchHistory);
+
+    // Up / Down: step through history entries starting with the text before the cursor
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Up, Modifiers::NONE),
+        Cmd::HistorySearchBackward,
+    );
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Down, Modifiers::NONE),
+        Cmd::HistorySearchForward,
+    );
+
+    // State shared by all commands in this session
+    let mut state = ShellState {
+        editor: rl,
+        executables: all_executables,
+        last_written_count: 0,
+        history_times: Vec::new(),
+        pending_input: None,
+        ai: AiSession::default(),
+        ai_conversation: Vec::new(),
+        options: ShellOptions::default(),
+        in_hook: false,
+        interactive: io::stdin().is_terminal(),
+        stdin_is_script: !io::stdin().is_terminal(),
+        subshell: false,
+        // Start from the inherited environment (skipping non-UTF-8 entries)
+        env: env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect(),
+        last_status: 0,
+        shell_pid: std::process::id(),
+        last_background_pid: None,
+        heredocs: VecDeque::new(),
+        aliases: HashMap::new(),
+        jobs: Vec::new(),
+        dir_stack: Vec::new(),
+        expansion_error: false,
+        errexit_abort: false,
+        #[cfg(unix)]
+        process_substitutions: Vec::new(),
+        traps: HashMap::new(),
+    };
+
     // Load history from HISTFILE at startup
     if let Ok(histfile_path) = env::var("HISTFILE") {
         if let Ok(content) = fs::read_to_string(&histfile_path) {
-            for line in content.lines() {
-                let trimmed = line.trim();
-                if !trimmed.is_empty() {
-                    let _ = rl.add_history_entry(trimmed);
-                }
+            for (entry, time) in parse_history_file(&content) {
+                state.add_history(&entry, time);
             }
         }
+        // Only commands run from here on are new to the file
+        state.last_written_count = state.editor.history().len();
     }
 
-    // Track the number of history entries at last file write
-    let mut last_written_count: usize = 0;
+    // Config values apply where the environment doesn't set them
+    for (name, value) in shell_config.env_defaults() {
+        state.env.entry(name.to_string()).or_insert(value);
+    }
+
+    // An inherited PWD may be stale (e.g. started via a symlink or `exec`),
+    // so start from the real working directory
+    if let Ok(cwd) = env::current_dir() {
+        state
+            .env
+            .insert("PWD".to_string(), cwd.display().to_string());
+    }
 
     loop {
+        // Run traps for signals caught while the last line ran
+        run_pending_traps(&mut state);
+
+        // Report background jobs that finished since the last prompt
+        reap_jobs(&mut state, false);
+
+        // Apply a HISTSIZE changed during the session
+        let limit = history_limit(state.var("HISTSIZE"));
+        let _ = state.editor.history_mut().set_max_len(limit);
+        state.sync_history_times();
+
+        // Run the precmd hook before drawing the prompt
+        run_hook("SHELL_PRECMD", None, &mut state);
+
+        // Completion and highlighting follow changes to PATH
+        state.executables.follow_path(state.var("PATH"));
+
+        // Let completion and highlighting see variables and aliases
+        // defined since the last prompt, and where `~` now points
+        let variables: Vec<String> = state.env.keys().cloned().collect();
+        let aliases: Vec<String> = state.aliases.keys().cloned().collect();
+        let home = state.var("HOME").map(|home| home.to_string());
+        if let Some(completer) = state.editor.helper_mut() {
+            completer.variables = variables;
+            completer.aliases = aliases;
+            completer.home = home;
+        }
+
         // Build prompt
-        let enable = env::var("ENABLE_CUR_DIR_DISPLAY").unwrap_or(String::from("false"));
-        let prompt = if enable == "true" {
-            let current = env::current_dir().unwrap_or_else(|_| PathBuf::from("?"));
-            let dir_name = current.file_name().and_then(|s| s.to_str()).unwrap_or("/");
-            format!("[{}] $ ", dir_name)
-        } else {
-            "$ ".to_string()
-        };
+        let prompt = render_prompt(&state);
+
+        // A right-aligned prompt is drawn by the highlighter, only on a terminal
+        let right_prompt = state
+            .var("RPROMPT")
+            .filter(|_| io::stdout().is_terminal())
+            .map(|template| expand_prompt(template, &state));
+        let prompt_width = display_width(prompt.rsplit('\n').next().unwrap_or_default());
+        if let Some(completer) = state.editor.helper_mut() {
+            completer.right_prompt = right_prompt;
+            completer.prompt_width = prompt_width;
+        }
 
-        // Read user input
-        match rl.readline(&prompt) {
+        // Read user input, starting from a suggestion picked for editing
+        let input = match state.pending_input.take() {
+            Some(initial) => state.editor.readline_with_initial(&prompt, (&initial, "")),
+            None => state.editor.readline(&prompt),
+        };
+        // Continuation lines have no right prompt
+        if let Some(completer) = state.editor.helper_mut() {
+            completer.right_prompt = None;
+        }
+        match input {
             Ok(line) => {
-                let trimmed = line.trim();
+                // Keep reading while the line ends in `\` or has an open quote
+                let Some(line) = read_continuation_lines(line, &mut state) else {
+                    continue;
+                };
+                let mut trimmed = line.trim().to_string();
+
+                // Expand !!, !n, !-n and !prefix, showing the result
+                match expand_history(&trimmed, &state.history()) {
+                    Ok(Some(expanded)) => {
+                        println!("{}", expanded);
+                        trimmed = expanded;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        state.last_status = 1;
+                        continue;
+                    }
+                }
+                let trimmed = trimmed.as_str();
+
                 if !trimmed.is_empty() {
-                    // Add to history
-                    let _ = rl.add_history_entry(trimmed);
-
-                    // Get history (excluding the current command being entered)
-                    let history: Vec<String> = rl.history().iter().map(|s| s.to_string()).collect();
-
-                    if let Err(e) = execute_command(
-                        trimmed,
-                        &all_executables,
-                        &history,
-                        &mut rl,
-                        &mut last_written_count,
-                    ) {
+                    // Add to history, unless HISTCONTROL says to skip it
+                    if !state.history_ignores(&line, trimmed) {
+                        state.add_history(trimmed, unix_time());
+                    }
+
+                    // Collect here-document bodies before running anything
+                    read_heredoc_bodies(trimmed, &mut state);
+
+                    // Run the preexec hook with the command text before dispatch
+                    run_hook("SHELL_PREEXEC", Some(trimmed), &mut state);
+
+                    state.errexit_abort = false;
+                    if let Err(e) = execute_line(trimmed, &mut state) {
                         eprintln!("Execution error: {}", e);
                     }
+
+                    // Like bash, `set -e` ends a script at the first failure
+                    if state.errexit_abort && !state.interactive {
+                        run_exit_trap(&mut state);
+                        save_history_to_histfile(&state);
+                        std::process::exit(state.last_status & 0xff);
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -205,8 +1198,7 @@ fn main() {
             }
             Err(ReadlineError::Eof) => {
                 // Ctrl-D: save history before exit
-                let history: Vec<String> = rl.history().iter().map(|s| s.to_string()).collect();
-                save_history_to_histfile(&history);
+                save_history_to_histfile(&state);
                 break;
             }
             Err(err) => {
@@ -215,746 +1207,5290 @@ fn main() {
             }
         }
     }
+
+    run_exit_trap(&mut state);
 }
 
-/// Execute command
-fn execute_command(
-    input: &str,
-    all_executables: &HashMap<String, PathBuf>,
-    history: &[String],
-    rl: &mut Editor<CommandCompleter, DefaultHistory>,
-    last_written_count: &mut usize,
-) -> io::Result<()> {
-    // 1. Parse: convert string input to strongly-typed enum
-    let (action, redirection) = parse_command(input, all_executables);
+/// Expand history references in a line typed at the prompt
+///
+/// `!!` is the previous command, `!n` entry n, `!-n` the nth previous entry
+/// and `!prefix` the latest entry starting with prefix. A line starting with
+/// `!` followed by a word is an AI request, so `!prefix` only expands later in
+/// the line. Nothing expands inside single quotes or after a backslash.
+///
+/// Returns `Ok(None)` if the line has no references, or an
+/// `event not found` error.
+fn expand_history(line: &str, history: &[String]) -> Result<Option<String>, String> {
+    let mut result = String::new();
+    let mut expanded = false;
+    let mut chars = line.char_indices().peekable();
+    let mut in_single_quote = false;
 
-    // 2. Execute: perform corresponding logic based on enum variant
-    match action {
-        CommandAction::Exit => {
-            // Save history to HISTFILE before exit
-            save_history_to_histfile(history);
-            std::process::exit(0);
-        }
-        CommandAction::Echo(args) => {
-            let output = args.join(" ");
-
-            // Handle stderr redirection (create file but don't write)
-            if let Some(ref redir) = redirection {
-                if let Some(stderr_file) = &redir.stderr_file {
-                    let _ = if redir.stderr_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stderr_file)
-                    } else {
-                        File::create(stderr_file)
-                    };
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                result.push(ch);
+                if let Some((_, next)) = chars.next() {
+                    result.push(next);
                 }
+                continue;
             }
-
-            if let Some(redir) = redirection {
-                // Redirect to file (only handle stdout redirection)
-                if let Some(stdout_file) = &redir.stdout_file {
-                    let file_result = if redir.stdout_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stdout_file)
-                    } else {
-                        File::create(stdout_file)
-                    };
-                    if let Ok(mut file) = file_result {
-                        let _ = writeln!(file, "{}", output);
+            '\'' => in_single_quote = !in_single_quote,
+            '!' if !in_single_quote => {
+                // Read the event designator: `!`, digits, `-digits` or a word
+                let mut event = String::new();
+                if let Some(&(_, '!')) = chars.peek() {
+                    event.push('!');
+                    chars.next();
+                } else {
+                    while let Some(&(_, c)) = chars.peek() {
+                        let allowed = if event.is_empty() {
+                            c == '-'
+                                || c.is_ascii_alphanumeric()
+                                || c == '_'
+                                || c == '.'
+                                || c == '/'
+                        } else if event.starts_with('-')
+                            || event.starts_with(|c: char| c.is_ascii_digit())
+                        {
+                            c.is_ascii_digit()
+                        } else {
+                            !c.is_whitespace() && !matches!(c, ';' | '|' | '&' | '\'' | '"')
+                        };
+                        if !allowed {
+                            break;
+                        }
+                        event.push(c);
+                        chars.next();
                     }
+                }
+
+                let is_prefix = !event.is_empty()
+                    && event != "!"
+                    && !event.starts_with('-')
+                    && !event.starts_with(|c: char| c.is_ascii_digit());
+                if event.is_empty() || (is_prefix && i == 0) || event == "-" {
+                    // A lone `!`, or an AI request: keep as typed
+                    result.push('!');
+                    result.push_str(&event);
+                    continue;
+                }
+
+                let entry = if event == "!" {
+                    history.last()
+                } else if let Some(n) = event.strip_prefix('-') {
+                    n.parse::<usize>()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .and_then(|n| history.len().checked_sub(n))
+                        .map(|index| &history[index])
+                } else if let Ok(n) = event.parse::<usize>() {
+                    n.checked_sub(1).and_then(|index| history.get(index))
                 } else {
-                    // No stdout redirection, output to standard output
-                    println!("{}", output);
+                    history.iter().rev().find(|entry| entry.starts_with(&event))
+                };
+
+                match entry {
+                    Some(entry) => {
+                        result.push_str(entry);
+                        expanded = true;
+                    }
+                    None => return Err(format!("!{}: event not found", event)),
                 }
-            } else {
-                // Output to standard output
-                println!("{}", output);
+                continue;
             }
+            _ => {}
         }
-        CommandAction::Ai(args) => {
-            generate_command_with_ai(args);
-        }
-        CommandAction::Type(args) => {
-            if let Some(target) = args.first() {
-                handle_type_logic(target);
-            }
-        }
-        CommandAction::Pwd => {
-            let output = format!("{}", env::current_dir()?.display());
+        result.push(ch);
+    }
 
-            // Handle stderr redirection (create file but don't write)
-            if let Some(ref redir) = redirection {
-                if let Some(stderr_file) = &redir.stderr_file {
-                    let _ = if redir.stderr_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stderr_file)
-                    } else {
-                        File::create(stderr_file)
-                    };
-                }
-            }
+    Ok(if expanded { Some(result) } else { None })
+}
 
-            if let Some(redir) = redirection {
-                // Redirect to file (only handle stdout redirection)
-                if let Some(stdout_file) = &redir.stdout_file {
-                    let file_result = if redir.stdout_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stdout_file)
-                    } else {
-                        File::create(stdout_file)
-                    };
-                    if let Ok(mut file) = file_result {
-                        let _ = writeln!(file, "{}", output);
-                    }
-                } else {
-                    println!("{}", output);
-                }
-            } else {
-                println!("{}", output);
-            }
-        }
-        CommandAction::External(command, args) => {
-            let mut cmd = Command::new(command);
-            cmd.args(args);
+/// Display a path with the home directory shortened to `~`
+fn abbreviate_home(path: &Path, state: &ShellState) -> String {
+    let path = path.display().to_string();
+    match state.var("HOME").and_then(|home| path.strip_prefix(home)) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+        _ => path,
+    }
+}
 
-            // If there's redirection, configure stdout and/or stderr
-            if let Some(redir) = redirection {
-                if let Some(stdout_file) = &redir.stdout_file {
-                    let file_result = if redir.stdout_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stdout_file)
-                    } else {
-                        File::create(stdout_file)
-                    };
-                    if let Ok(file) = file_result {
-                        cmd.stdout(Stdio::from(file));
-                    }
-                }
-                if let Some(stderr_file) = &redir.stderr_file {
-                    let file_result = if redir.stderr_append {
-                        OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .append(true)
-                            .open(stderr_file)
-                    } else {
-                        File::create(stderr_file)
-                    };
-                    if let Ok(file) = file_result {
-                        cmd.stderr(Stdio::from(file));
-                    }
-                }
-            }
+/// Change the shell's working directory, updating `PWD` and `OLDPWD`
+///
+/// Errors are reported with the builtin's name; returns whether it succeeded.
+fn change_directory(builtin: &str, target: &Path, state: &mut ShellState) -> bool {
+    /*  Why use set_current_dir?
 
-            let _ = cmd.status();
+    At the OS level, cd cannot run as an external program (like /bin/cd) because it must change the state of the current Shell process.
+    If you call an external cd script in the Shell, it only changes that subprocess's directory, and when it returns to the Shell, the path remains unchanged.
+    Through std::env::set_current_dir, you directly trigger the OS's chdir system call.
+    */
+    let previous = env::current_dir().ok();
+    if let Err(e) = env::set_current_dir(target) {
+        let error_msg = match e.kind() {
+            io::ErrorKind::NotFound => "No such file or directory",
+            io::ErrorKind::PermissionDenied => "Permission denied",
+            io::ErrorKind::NotADirectory => "Not a directory", // Note: some systems support this Kind
+            _ => "Unknown error",
+        };
+        eprintln!("{}: {}: {}", builtin, target.display(), error_msg);
+        if e.kind() == io::ErrorKind::NotFound
+            && let Some(suggestion) = similar_directory(target)
+        {
+            eprintln!("{}: did you mean '{}'?", builtin, suggestion);
         }
-        CommandAction::Cd(args) => {
-            /*  Why use set_current_dir?
+        return false;
+    }
 
-            At the OS level, cd cannot run as an external program (like /bin/cd) because it must change the state of the current Shell process.
-            If you call an external cd script in the Shell, it only changes that subprocess's directory, and when it returns to the Shell, the path remains unchanged.
-            Through std::env::set_current_dir, you directly trigger the OS's chdir system call.
-            */
-            let arg_str = args.first().map(|s| s.as_str()).unwrap_or("");
-            let target_path = if arg_str.is_empty() || arg_str == "~" {
-                // Handle cd or cd ~, jump to HOME
-                env::var("HOME")
-                    .map(PathBuf::from)
-                    .unwrap_or_else(|_| PathBuf::from("/"))
-            } else {
-                PathBuf::from(arg_str)
-            };
+    // Remember where we came from for `cd -`, and point PWD at the
+    // canonical new directory so child processes see where they run
+    if let Some(previous) = previous {
+        state
+            .env
+            .insert("OLDPWD".to_string(), previous.display().to_string());
+    }
+    if let Ok(current) = env::current_dir() {
+        let current = fs::canonicalize(&current).unwrap_or(current);
+        state
+            .env
+            .insert("PWD".to_string(), current.display().to_string());
+    }
+    true
+}
 
-            if let Err(e) = env::set_current_dir(&target_path) {
-                let error_msg = match e.kind() {
-                    io::ErrorKind::NotFound => "No such file or directory",
-                    io::ErrorKind::PermissionDenied => "Permission denied",
-                    io::ErrorKind::NotADirectory => "Not a directory", // Note: some systems support this Kind
-                    _ => "Unknown error",
-                };
-                eprintln!("cd: {}: {}", target_path.display(), error_msg);
+/// Find the directory a relative `cd` argument names under one of the
+/// `:`-separated `CDPATH` base directories
+///
+/// Absolute paths and ones starting with `.` or `..` aren't looked up. An
+/// empty entry stands for the current directory, which needs no lookup.
+fn cdpath_target(arg: &str, cdpath: Option<&str>) -> Option<PathBuf> {
+    let first = Path::new(arg).components().next()?;
+    if !matches!(first, std::path::Component::Normal(_)) {
+        return None;
+    }
+    for base in cdpath?.split(':') {
+        if base.is_empty() {
+            if Path::new(arg).is_dir() {
+                return None;
             }
+        } else if Path::new(base).join(arg).is_dir() {
+            return Some(Path::new(base).join(arg));
         }
-        CommandAction::Unknown(cmd) => {
-            eprintln!("{}: command not found", cmd);
+    }
+    None
+}
+
+/// The directory next to a missing `target` whose name is closest to its
+/// name, e.g. `Desktop` for `Dektop`
+fn similar_directory(target: &Path) -> Option<String> {
+    let name = target.file_name()?.to_str()?;
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let siblings: Vec<String> = fs::read_dir(parent)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    closest_match(name, siblings.iter().map(String::as_str)).map(str::to_string)
+}
+
+/// The candidate closest to `name` by edit distance, if it's close enough to
+/// be a likely typo (one or two edits, fewer for short names)
+fn closest_match<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 2).min(2);
+    candidates
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance: the fewest single-character insertions, deletions and
+/// substitutions that turn `a` into `b`, counting a swap of two adjacent
+/// characters (a common typo, like `ehco`) as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j]: distance between the first i chars of a and j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
         }
-        CommandAction::Pipeline(commands) => {
-            execute_pipeline(commands)?;
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Print the current directory followed by the `pushd` stack, like `dirs`
+fn print_dir_stack(state: &ShellState) {
+    let current = env::current_dir().unwrap_or_else(|_| PathBuf::from("?"));
+    let entries: Vec<String> = std::iter::once(&current)
+        .chain(state.dir_stack.iter().rev())
+        .map(|dir| abbreviate_home(dir, state))
+        .collect();
+    println!("{}", entries.join(" "));
+}
+
+/// Build the prompt from the `PS1` template
+///
+/// Without `PS1`, the prompt is `$ `, or `[dir] $ ` when
+/// `ENABLE_CUR_DIR_DISPLAY=true`.
+fn render_prompt(state: &ShellState) -> String {
+    match state.var("PS1") {
+        Some(template) => expand_prompt(template, state),
+        None if state.var("ENABLE_CUR_DIR_DISPLAY") == Some("true") => {
+            let current = env::current_dir().unwrap_or_else(|_| PathBuf::from("?"));
+            let dir_name = current.file_name().and_then(|s| s.to_str()).unwrap_or("/");
+            format!("[{}] $ ", dir_name)
         }
-        CommandAction::History(limit) => {
-            // Decide how many history entries to show based on limit parameter
-            let (items_to_show, start_index) = if let Some(n) = limit {
-                // Show last n entries
-                let start = history.len().saturating_sub(n);
-                (&history[start..], start)
-            } else {
-                // Show all entries
-                (&history[..], 0)
-            };
+        None => "$ ".to_string(),
+    }
+}
 
-            // Display history, format: "    <line_number>  <command>"
-            for (i, cmd) in items_to_show.iter().enumerate() {
-                println!("    {}  {}", start_index + i + 1, cmd);
-            }
+/// Expand a prompt template (`PS1` or `RPROMPT`)
+///
+/// Supports `\w` (cwd, with `~` for home), `\W` (cwd basename), `\u` (user),
+/// `\h` (short hostname), `\$` (`#` for root, else `$`), `\t` (time as
+/// `HH:MM:SS`), `\?` (last exit status), `\n` and `\\`.
+fn expand_prompt(template: &str, state: &ShellState) -> String {
+    let current = env::current_dir().unwrap_or_else(|_| PathBuf::from("?"));
+    let dir_name = current
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("/")
+        .to_string();
+
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            prompt.push(ch);
+            continue;
         }
-        CommandAction::HistoryRead(path) => {
-            // Read history from file and append to in-memory history list
-            match fs::read_to_string(&path) {
-                Ok(content) => {
-                    for line in content.lines() {
-                        let trimmed = line.trim();
-                        if !trimmed.is_empty() {
-                            let _ = rl.add_history_entry(trimmed);
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("history: {}: {}", path, e);
-                }
+        match chars.next() {
+            Some('w') => prompt.push_str(&abbreviate_home(&current, state)),
+            Some('W') => prompt.push_str(&dir_name),
+            Some('u') => match state.var("USER") {
+                Some(user) => prompt.push_str(user),
+                None => prompt.push_str(&current_user_name()),
+            },
+            Some('h') => prompt.push_str(&short_hostname()),
+            Some('$') => prompt.push(if is_root() { '#' } else { '$' }),
+            Some('t') => prompt.push_str(&format_time("%H:%M:%S", unix_time())),
+            Some('?') => prompt.push_str(&state.last_status.to_string()),
+            Some('n') => prompt.push('\n'),
+            Some('\\') => prompt.push('\\'),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
             }
+            None => prompt.push('\\'),
         }
-        CommandAction::HistoryWrite(path) => {
-            // Write history to file
-            match File::create(&path) {
-                Ok(mut file) => {
-                    // Write all history entries, one command per line
-                    for cmd in history {
-                        if let Err(e) = writeln!(file, "{}", cmd) {
-                            eprintln!("history: {}: {}", path, e);
-                            return Ok(());
-                        }
-                    }
-                    // Update the count of written entries
-                    *last_written_count = history.len();
-                }
-                Err(e) => {
-                    eprintln!("history: {}: {}", path, e);
-                }
+    }
+    prompt
+}
+
+/// The current time, in seconds since the Unix epoch
+fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Format a Unix time as local time with `strftime` codes (e.g. `%F %T`)
+#[cfg(unix)]
+fn format_time(format: &str, time: i64) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let mut buffer = [0u8; 256];
+    let len = unsafe {
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// Format a Unix time as local time with the common `strftime` codes
+/// (`%Y %m %d %H %M %S %F %T %%`); others are kept as written
+#[cfg(not(unix))]
+fn format_time(format: &str, time: i64) -> String {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_s(&mut tm, &time) } != 0 {
+        return String::new();
+    }
+    let date = format!(
+        "{}-{:02}-{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    );
+    let clock = format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec);
+
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => result.push_str(&(tm.tm_year + 1900).to_string()),
+            Some('m') => result.push_str(&format!("{:02}", tm.tm_mon + 1)),
+            Some('d') => result.push_str(&format!("{:02}", tm.tm_mday)),
+            Some('H') => result.push_str(&format!("{:02}", tm.tm_hour)),
+            Some('M') => result.push_str(&format!("{:02}", tm.tm_min)),
+            Some('S') => result.push_str(&format!("{:02}", tm.tm_sec)),
+            Some('F') => result.push_str(&date),
+            Some('T') => result.push_str(&clock),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
             }
+            None => result.push('%'),
         }
-        CommandAction::HistoryAppend(path) => {
-            // Append new history to file
-            match OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&path)
-            {
-                Ok(mut file) => {
-                    // Only append new commands since last write
-                    let new_commands = &history[*last_written_count..];
-                    for cmd in new_commands {
-                        if let Err(e) = writeln!(file, "{}", cmd) {
-                            eprintln!("history: {}: {}", path, e);
-                            return Ok(());
-                        }
+    }
+    result
+}
+
+/// Number of terminal columns `text` takes, skipping ANSI escape sequences
+/// (counting each character as one column)
+fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            // Skip `ESC [ params final`, or a two-character escape
+            if chars.next() == Some('[') {
+                for ch in chars.by_ref() {
+                    if ch.is_ascii_alphabetic() {
+                        break;
                     }
-                    // Update the count of written entries
-                    *last_written_count = history.len();
-                }
-                Err(e) => {
-                    eprintln!("history: {}: {}", path, e);
                 }
             }
+        } else {
+            width += 1;
         }
     }
+    width
+}
 
-    Ok(())
+/// Whether the shell runs as root, for the `\$` prompt escape
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
 }
 
-/// Parser: responsible for command dispatch logic
-fn parse_command(
-    input: &str,
-    all_executables: &HashMap<String, PathBuf>,
-) -> (CommandAction, Option<Redirection>) {
-    // First check if it's an AI command (starts with !)
-    let trimmed = input.trim();
-    if trimmed.starts_with('!') {
-        // Extract all content after ! as AI prompt
-        let prompt = trimmed[1..].trim();
-        let prompt_tokens: Vec<String> = prompt.split_whitespace().map(|s| s.to_string()).collect();
-        return (CommandAction::Ai(prompt_tokens), None);
+/// Whether the shell runs as root; there is no root user to check for here
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Name of the effective user from the password database
+#[cfg(unix)]
+fn current_user_name() -> String {
+    let entry = unsafe { libc::getpwuid(libc::geteuid()) };
+    if entry.is_null() {
+        return String::new();
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr((*entry).pw_name) };
+    name.to_string_lossy().into_owned()
+}
+
+/// Name of the current user, from `USERNAME`
+#[cfg(not(unix))]
+fn current_user_name() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
+/// The machine's hostname up to the first `.`
+#[cfg(unix)]
+fn short_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return String::new();
     }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    let name = String::from_utf8_lossy(&buffer[..end]);
+    name.split('.').next().unwrap_or_default().to_string()
+}
 
-    // First check if there's a pipeline
-    let pipeline_parts = parse_pipeline(input);
+/// The machine's name, from `COMPUTERNAME`
+#[cfg(not(unix))]
+fn short_hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
 
-    if pipeline_parts.len() > 1 {
-        // Has pipeline, parse each part
-        let mut commands = Vec::new();
+/// Remove finished background jobs, printing a `Done` line for each
+///
+/// With `list_running`, still-running jobs are listed too (the `jobs` builtin).
+/// The most recent job is marked `+` and the one before it `-`.
+fn reap_jobs(state: &mut ShellState, list_running: bool) {
+    let count = state.jobs.len();
+    let mut index = 0;
+    state.jobs.retain_mut(|job| {
+        let marker = match count - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        };
+        index += 1;
 
-        for part in pipeline_parts {
-            let (command_part, _) = parse_redirection(&part);
-            let tokens = parse_args(&command_part);
+        let (status, finished) = match job.child.try_wait() {
+            Ok(Some(status)) => match status.code() {
+                Some(0) => ("Done".to_string(), true),
+                Some(code) => (format!("Exit {}", code), true),
+                None => (format!("Killed ({})", exit_status_code(status)), true),
+            },
+            Ok(None) => ("Running".to_string(), false),
+            Err(_) => ("Done".to_string(), true),
+        };
+
+        if finished {
+            println!("[{}]{}  {:<24}{}", job.id, marker, status, job.command);
+        } else if list_running {
+            println!("[{}]{}  {:<24}{} &", job.id, marker, status, job.command);
+        }
+        !finished
+    });
+}
 
-            if !tokens.is_empty() {
-                let command = tokens[0].clone();
-                let args = tokens[1..].to_vec();
-                commands.push((command, args));
+/// Run `test` or `[`, given the command name followed by its arguments
+///
+/// Returns 0 if the expression is true, 1 if false and 2 on a syntax error.
+fn run_test(tokens: &[String]) -> i32 {
+    let name = tokens[0].as_str();
+    let mut args = &tokens[1..];
+    if name == "[" {
+        match args.split_last() {
+            Some((last, rest)) if last == "]" => args = rest,
+            _ => {
+                eprintln!("[: missing `]'");
+                return 2;
             }
         }
+    }
 
-        return (CommandAction::Pipeline(commands), None);
+    // An empty expression is false
+    if args.is_empty() {
+        return 1;
     }
 
-    // First check if there are redirection operators
-    let (command_part, redirection) = parse_redirection(input);
+    let mut parser = TestParser { args, pos: 0 };
+    let result = parser.or_expr().and_then(|value| match parser.peek() {
+        None => Ok(value),
+        Some(_) => Err("too many arguments".to_string()),
+    });
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            2
+        }
+    }
+}
 
-    // Parse the entire command line, get command and arguments
-    let tokens = parse_args(&command_part);
+/// Recursive-descent evaluator for `test` expressions
+///
+/// Precedence from lowest: `-o`, `-a`, `!`, then `( ... )` and primaries.
+struct TestParser<'a> {
+    args: &'a [String],
+    pos: usize,
+}
 
-    if tokens.is_empty() {
-        return (CommandAction::Unknown(String::new()), redirection);
+impl TestParser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.args.get(self.pos).map(|s| s.as_str())
     }
 
-    let command = &tokens[0];
-    let args: Vec<String> = tokens[1..].to_vec();
+    fn next(&mut self) -> Result<&str, String> {
+        let arg = self
+            .args
+            .get(self.pos)
+            .ok_or_else(|| "argument expected".to_string())?;
+        self.pos += 1;
+        Ok(arg)
+    }
 
-    let action = match command.as_str() {
-        "exit" => CommandAction::Exit,
-        "echo" => CommandAction::Echo(args),
-        "pwd" => CommandAction::Pwd,
-        "type" => CommandAction::Type(args),
-        "cd" => CommandAction::Cd(args),
-        "history" => {
-            // Check if it's -r option (read history from file)
-            if args.first().map(|s| s.as_str()) == Some("-r") {
-                if let Some(path) = args.get(1) {
-                    CommandAction::HistoryRead(path.clone())
-                } else {
-                    // -r option missing file path parameter
-                    CommandAction::Unknown("history".to_string())
-                }
-            } else if args.first().map(|s| s.as_str()) == Some("-w") {
-                // Check if it's -w option (write history to file)
-                if let Some(path) = args.get(1) {
-                    CommandAction::HistoryWrite(path.clone())
-                } else {
-                    // -w option missing file path parameter
-                    CommandAction::Unknown("history".to_string())
-                }
-            } else if args.first().map(|s| s.as_str()) == Some("-a") {
-                // Check if it's -a option (append new history to file)
-                if let Some(path) = args.get(1) {
-                    CommandAction::HistoryAppend(path.clone())
-                } else {
-                    // -a option missing file path parameter
-                    CommandAction::Unknown("history".to_string())
-                }
-            } else {
-                // Parse optional numeric parameter
-                let limit = args.first().and_then(|s| s.parse::<usize>().ok());
-                CommandAction::History(limit)
-            }
-        }
-        _ => {
-            // Check if in preloaded external command cache
-            if all_executables.contains_key(command) {
-                CommandAction::External(command.to_string(), args)
-            } else {
-                CommandAction::Unknown(command.to_string())
-            }
+    fn or_expr(&mut self) -> Result<bool, String> {
+        let mut value = self.and_expr()?;
+        while self.peek() == Some("-o") {
+            self.pos += 1;
+            // Evaluate both sides so syntax errors are always reported
+            value = self.and_expr()? || value;
         }
-    };
+        Ok(value)
+    }
 
-    (action, redirection)
-}
+    fn and_expr(&mut self) -> Result<bool, String> {
+        let mut value = self.not_expr()?;
+        while self.peek() == Some("-a") {
+            self.pos += 1;
+            value = self.not_expr()? && value;
+        }
+        Ok(value)
+    }
 
-/// Parse pipeline: split commands by | but ignore | inside quotes
-fn parse_pipeline(input: &str) -> Vec<String> {
-    let mut commands = Vec::new();
-    let mut current = String::new();
-    let mut chars = input.chars().peekable();
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    let mut escaped = false;
+    fn not_expr(&mut self) -> Result<bool, String> {
+        // A lone `!` is just a non-empty string
+        if self.peek() == Some("!") && self.pos + 1 < self.args.len() {
+            self.pos += 1;
+            return Ok(!self.not_expr()?);
+        }
+        self.primary()
+    }
 
-    while let Some(ch) = chars.next() {
-        if escaped {
-            current.push(ch);
-            escaped = false;
-            continue;
+    fn primary(&mut self) -> Result<bool, String> {
+        // A binary operator after the next word takes precedence, so `[ -f = -f ]` compares strings
+        if let Some(op) = self.args.get(self.pos + 1).map(|s| s.as_str())
+            && is_test_binary_op(op)
+            && self.pos + 2 < self.args.len()
+        {
+            let left = self.next()?.to_string();
+            self.pos += 1;
+            let right = self.next()?.to_string();
+            return test_binary(&left, op, &right);
         }
 
-        match ch {
-            '\\' if !in_single_quote => {
-                escaped = true;
-                current.push(ch);
-            }
-            '\'' if !in_double_quote => {
-                in_single_quote = !in_single_quote;
-                current.push(ch);
-            }
-            '"' if !in_single_quote => {
-                in_double_quote = !in_double_quote;
-                current.push(ch);
-            }
-            '|' if !in_single_quote && !in_double_quote => {
-                // Found pipe, save current command
-                if !current.trim().is_empty() {
-                    commands.push(current.trim().to_string());
-                    current.clear();
+        let arg = self.next()?.to_string();
+        match arg.as_str() {
+            "(" if self.pos < self.args.len() => {
+                let value = self.or_expr()?;
+                match self.next() {
+                    Ok(")") => Ok(value),
+                    _ => Err("`)' expected".to_string()),
                 }
             }
-            _ => {
-                current.push(ch);
+            op if op.len() == 2 && op.starts_with('-') && self.pos < self.args.len() => {
+                let operand = self.next()?.to_string();
+                test_unary(op, &operand)
             }
+            // A single word is true if it's non-empty
+            _ => Ok(!arg.is_empty()),
         }
     }
+}
 
-    // Add the last command
-    if !current.trim().is_empty() {
-        commands.push(current.trim().to_string());
-    }
+/// Whether `op` is a binary `test` operator
+fn is_test_binary_op(op: &str) -> bool {
+    matches!(
+        op,
+        "=" | "==" | "!=" | "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge"
+    )
+}
 
-    // If no pipeline, return single command
-    if commands.is_empty() {
-        vec![input.to_string()]
-    } else {
-        commands
+/// Evaluate a binary `test` comparison
+fn test_binary(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    match op {
+        "=" | "==" => return Ok(left == right),
+        "!=" => return Ok(left != right),
+        _ => {}
     }
+
+    let parse = |s: &str| {
+        s.trim()
+            .parse::<i64>()
+            .map_err(|_| format!("{}: integer expression expected", s))
+    };
+    let (left, right) = (parse(left)?, parse(right)?);
+    Ok(match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    })
 }
 
-/// Parse redirection operators, return command part and redirection info
-fn parse_redirection(input: &str) -> (String, Option<Redirection>) {
+/// Evaluate a unary `test` operator: file and string checks
+fn test_unary(op: &str, operand: &str) -> Result<bool, String> {
+    let path = Path::new(operand);
+    Ok(match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-e" => path.exists(),
+        "-f" => path.is_file(),
+        "-d" => path.is_dir(),
+        "-s" => fs::metadata(path).is_ok_and(|m| m.len() > 0),
+        "-L" | "-h" => path.is_symlink(),
+        "-r" => file_access(operand, 'r'),
+        "-w" => file_access(operand, 'w'),
+        "-x" => file_access(operand, 'x'),
+        _ => return Err(format!("{}: unary operator expected", op)),
+    })
+}
+
+/// Check whether the current user may read (`r`), write (`w`) or execute
+/// (`x`) a file
+#[cfg(unix)]
+fn file_access(path: &str, mode: char) -> bool {
+    let mode = match mode {
+        'r' => libc::R_OK,
+        'w' => libc::W_OK,
+        _ => libc::X_OK,
+    };
+    match std::ffi::CString::new(path) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Check whether a file may be read (`r`), written (`w`) or executed (`x`),
+/// judged by the file itself: any file can be read, one not marked read-only
+/// written, and one with a `PATHEXT` extension run
+#[cfg(not(unix))]
+fn file_access(path: &str, mode: char) -> bool {
+    let path = Path::new(path);
+    match mode {
+        'r' => path.exists(),
+        'w' => fs::metadata(path).is_ok_and(|m| !m.permissions().readonly()),
+        _ => is_executable(path),
+    }
+}
+
+/// Wait for a background job in the foreground, returning its exit status
+///
+/// On a terminal, the job's process group is given control of it so keys
+/// like Ctrl-C reach the job, and control is taken back once it exits.
+#[cfg(unix)]
+fn foreground_job(mut job: Job, interactive: bool) -> i32 {
+    println!("{}", job.command);
+    let pgid = job.child.id() as libc::pid_t;
+
+    unsafe {
+        if interactive {
+            libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        }
+        // Resume it in case it was stopped
+        libc::kill(-pgid, libc::SIGCONT);
+    }
+
+    let status = match job.child.wait() {
+        Ok(status) => {
+            if interrupted(status) {
+                println!();
+            }
+            exit_status_code(status)
+        }
+        Err(e) => {
+            eprintln!("fg: {}", e);
+            1
+        }
+    };
+
+    if interactive {
+        // The shell is now in the background, so ignore SIGTTOU while
+        // taking the terminal back
+        unsafe {
+            let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+            libc::signal(libc::SIGTTOU, previous);
+        }
+    }
+
+    status
+}
+
+/// Wait for a background job in the foreground, returning its exit status
+///
+/// There is no job control here, so the job can't be given the terminal.
+#[cfg(not(unix))]
+fn foreground_job(mut job: Job, _interactive: bool) -> i32 {
+    println!("{}", job.command);
+    match job.child.wait() {
+        Ok(status) => {
+            if interrupted(status) {
+                println!();
+            }
+            exit_status_code(status)
+        }
+        Err(e) => {
+            eprintln!("fg: {}", e);
+            1
+        }
+    }
+}
+
+/// Run the hook command stored in the environment variable `var`, if any
+///
+/// `arg` is passed to the hook as a single quoted argument. Hooks never run
+/// while another hook is running, so a failing hook can't trigger itself.
+fn run_hook(var: &str, arg: Option<&str>, state: &mut ShellState) {
+    let hook = match state.var(var) {
+        Some(hook) if !hook.trim().is_empty() => hook.to_string(),
+        _ => return,
+    };
+    if state.in_hook {
+        return;
+    }
+
+    let command = match arg {
+        // Single-quote the argument, closing and escaping any embedded quote
+        Some(arg) => format!("{} '{}'", hook, arg.replace('\'', "'\\''")),
+        None => hook,
+    };
+
+    // Hooks must not clobber the user's `$?`
+    let saved_status = state.last_status;
+    state.in_hook = true;
+    if let Err(e) = execute_line(&command, state) {
+        eprintln!("{}: {}", var, e);
+    }
+    state.in_hook = false;
+    state.last_status = saved_status;
+}
+
+/// Execute a line that may chain commands with `&&`, `||` and `;`
+///
+/// Each command is skipped unless its connector's condition holds for the
+/// last exit status, so `a && b || c` behaves like in other shells.
+fn execute_line(input: &str, state: &mut ShellState) -> io::Result<()> {
+    let input = strip_comment(input);
+    if input.trim().is_empty() {
+        return Ok(());
+    }
+
+    // AI requests are free text: operators in them aren't shell syntax
+    if input.trim_start().starts_with('!') {
+        return execute_command(input, state);
+    }
+
+    let list = parse_command_list(input);
+    if list.iter().any(|(_, command)| command.is_empty()) {
+        eprintln!("syntax error: missing command around `&&', `||' or `;'");
+        state.last_status = 2;
+        return Ok(());
+    }
+
+    for (index, (connector, command)) in list.iter().enumerate() {
+        let run = match connector {
+            Connector::Always => true,
+            Connector::And => state.last_status == 0,
+            Connector::Or => state.last_status != 0,
+        };
+        if !run {
+            continue;
+        }
+        let result = execute_command(command, state);
+        finish_process_substitutions(state);
+        result?;
+
+        // With `set -e` a failure stops everything, unless `&&` or `||` tests it
+        let tested = matches!(
+            list.get(index + 1),
+            Some((Connector::And | Connector::Or, _))
+        );
+        if state.options.errexit && state.last_status != 0 && !tested {
+            state.errexit_abort = true;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Execute command
+fn execute_command(input: &str, state: &mut ShellState) -> io::Result<()> {
+    // 1. Parse: convert string input to strongly-typed enum
+    let (action, redirection) = parse_command(input, state);
+    run_action(input, action, redirection, state)
+}
+
+/// Run the command parsed from `input`, setting `$?` to its exit status
+fn run_action(
+    input: &str,
+    action: CommandAction,
+    redirection: Option<Redirection>,
+    state: &mut ShellState,
+) -> io::Result<()> {
+    let history = state.history();
+
+    // 2. Execute: perform corresponding logic based on enum variant,
+    // yielding the command's exit status
+    let status = match action {
+        CommandAction::Exit(code) => {
+            let code = code.unwrap_or(state.last_status);
+            run_exit_trap(state);
+            // Save history to HISTFILE before exit (the shell that forked a
+            // subshell saves its own)
+            if !state.subshell {
+                save_history_to_histfile(state);
+            }
+            // Like other shells, only the low 8 bits of the status are kept
+            std::process::exit(code & 0xff);
+        }
+        CommandAction::Echo(args) => {
+            let output = echo_output(&args);
+
+            // Open redirection targets first so a refused overwrite aborts the command
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut file) = files.stdout {
+                // Redirect to file
+                let _ = write!(file, "{}", output);
+            } else {
+                // Output to standard output
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            0
+        }
+        CommandAction::Printf(args) => {
+            let (output, ok) = printf_output(&args);
+
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            if ok { 0 } else { 1 }
+        }
+        CommandAction::Ai(args, pipeline) => {
+            state.pending_input = generate_command_with_ai(
+                args,
+                pipeline,
+                &state.env,
+                &mut state.ai,
+                &mut state.ai_conversation,
+            );
+            0
+        }
+        CommandAction::AiExplain(args) => {
+            explain_command_with_ai(args, &state.env, &mut state.ai);
+            0
+        }
+        CommandAction::Type(args) => {
+            let (output, status) = type_output(&args, state.var("PATH"));
+
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Which(args) => {
+            state.executables.follow_path(state.var("PATH"));
+            let mut output = String::new();
+            let mut status = 0;
+            for name in &args {
+                // A program installed since the cache was built isn't in it yet
+                let path = state
+                    .executables
+                    .get(name)
+                    .or_else(|| find_command_in_path(name, state.var("PATH")));
+                match path {
+                    Some(path) => output.push_str(&format!("{}\n", path.display())),
+                    None => status = 1,
+                }
+            }
+
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Pwd => {
+            let output = format!("{}", env::current_dir()?.display());
+
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut file) = files.stdout {
+                let _ = writeln!(file, "{}", output);
+            } else {
+                println!("{}", output);
+            }
+            0
+        }
+        CommandAction::Background(command, args, assignments) => {
+            let mut cmd = Command::new(&command);
+            cmd.args(args)
+                .env_clear()
+                .envs(&state.env)
+                .envs(assignments);
+
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            // Background jobs never read the terminal, and get their own
+            // process group so Ctrl-C at the prompt doesn't reach them
+            cmd.stdin(files.stdin.map_or_else(Stdio::null, Stdio::from));
+            #[cfg(unix)]
+            cmd.process_group(0);
+            if let Some(file) = files.stdout {
+                cmd.stdout(Stdio::from(file));
+            }
+            if let Some(file) = files.stderr {
+                cmd.stderr(Stdio::from(file));
+            }
+
+            match cmd.spawn() {
+                Ok(child) => {
+                    let id = state.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+                    println!("[{}] {}", id, child.id());
+                    state.last_background_pid = Some(child.id());
+                    state.jobs.push(Job {
+                        id,
+                        child,
+                        command: strip_background(input).0.trim().to_string(),
+                    });
+                    0
+                }
+                Err(e) => spawn_error_status(&command, e),
+            }
+        }
+        CommandAction::External(command, args, assignments) => {
+            let mut cmd = Command::new(&command);
+            cmd.args(args)
+                .env_clear()
+                .envs(&state.env)
+                .envs(assignments);
+
+            // If there's redirection, configure stdin, stdout and/or stderr
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            if let Some(file) = files.stdin {
+                cmd.stdin(Stdio::from(file));
+            } else if state.stdin_is_script {
+                // Don't let the command read (or hang on) the shell's own input
+                cmd.stdin(Stdio::null());
+            }
+            if let Some(file) = files.stdout {
+                cmd.stdout(Stdio::from(file));
+            }
+            if let Some(file) = files.stderr {
+                cmd.stderr(Stdio::from(file));
+            }
+
+            match cmd.status() {
+                Ok(status) => {
+                    // Like bash, end the line the interrupted command left unfinished
+                    if interrupted(status) {
+                        println!();
+                    }
+                    exit_status_code(status)
+                }
+                Err(e) => spawn_error_status(&command, e),
+            }
+        }
+        CommandAction::Cd(args) => {
+            let arg_str = args.first().map(|s| s.as_str()).unwrap_or("");
+            // Like bash, show where a `-` or `CDPATH` lookup led
+            let mut print_dir = arg_str == "-";
+            let target_path = if arg_str.is_empty() || arg_str == "~" {
+                // Handle cd or cd ~, jump to HOME
+                state
+                    .var("HOME")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("/"))
+            } else if arg_str == "-" {
+                // Handle cd -, jump back to the previous directory
+                match state.var("OLDPWD") {
+                    Some(old) => PathBuf::from(old),
+                    None => {
+                        eprintln!("cd: OLDPWD not set");
+                        state.last_status = 1;
+                        return Ok(());
+                    }
+                }
+            } else if let Some(dir) = cdpath_target(arg_str, state.var("CDPATH")) {
+                print_dir = true;
+                dir
+            } else {
+                PathBuf::from(arg_str)
+            };
+
+            if !change_directory("cd", &target_path, state) {
+                1
+            } else {
+                if print_dir {
+                    println!("{}", state.var("PWD").unwrap_or_default());
+                }
+                0
+            }
+        }
+        CommandAction::Pushd(arg) => {
+            let current = env::current_dir()?;
+            let target = match &arg {
+                Some(dir) => PathBuf::from(dir),
+                // Without an argument, swap the current directory with the top of the stack
+                None => match state.dir_stack.pop() {
+                    Some(top) => top,
+                    None => {
+                        eprintln!("pushd: no other directory");
+                        state.last_status = 1;
+                        return Ok(());
+                    }
+                },
+            };
+            if change_directory("pushd", &target, state) {
+                state.dir_stack.push(current);
+                print_dir_stack(state);
+                0
+            } else {
+                if arg.is_none() {
+                    state.dir_stack.push(target);
+                }
+                1
+            }
+        }
+        CommandAction::Popd => match state.dir_stack.pop() {
+            Some(top) => {
+                if change_directory("popd", &top, state) {
+                    print_dir_stack(state);
+                    0
+                } else {
+                    state.dir_stack.push(top);
+                    1
+                }
+            }
+            None => {
+                eprintln!("popd: directory stack empty");
+                1
+            }
+        },
+        CommandAction::Dirs => {
+            print_dir_stack(state);
+            0
+        }
+        CommandAction::Failed(status) => status,
+        CommandAction::Unknown(cmd) => {
+            // Suggest a builtin or executable the name may be a typo of,
+            // without waiting for a scan of PATH that's still running
+            let executables = state.executables.scanned_names().unwrap_or_default();
+            let candidates = BUILTINS
+                .iter()
+                .copied()
+                .chain(executables.iter().map(String::as_str));
+            match closest_match(&cmd, candidates) {
+                Some(suggestion) => {
+                    eprintln!("{}: command not found. Did you mean '{}'?", cmd, suggestion)
+                }
+                None => eprintln!("{}: command not found", cmd),
+            }
+            127
+        }
+        CommandAction::Pipeline(commands) => execute_pipeline(commands, state)?,
+        CommandAction::History(selection) => {
+            write_paged(&history_output(&state.listed_history(), selection), state);
+            0
+        }
+        CommandAction::HistoryRead(path) => {
+            // Read history from file and append to in-memory history list
+            match fs::read_to_string(&path) {
+                Ok(content) => {
+                    for (entry, time) in parse_history_file(&content) {
+                        state.add_history(&entry, time);
+                    }
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", path, e);
+                    1
+                }
+            }
+        }
+        CommandAction::HistoryWrite(path, selection) => {
+            // Write the selected history entries, one command per line
+            let (start_index, end_index) = selection.bounds(history.len());
+            let entries = state.history_entries(start_index..end_index);
+            match write_history_file(&path, &entries, state) {
+                Ok(()) => {
+                    // Update the count of written entries (a partial range leaves it alone)
+                    if matches!(selection, HistorySelection::All) {
+                        state.last_written_count = history.len();
+                    }
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", path, e);
+                    1
+                }
+            }
+        }
+        CommandAction::HistoryClear => {
+            let _ = state.editor.clear_history();
+            state.history_times.clear();
+            state.last_written_count = 0;
+            0
+        }
+        CommandAction::HistoryDelete(number) => match number.parse::<usize>() {
+            Ok(n) if (1..=history.len()).contains(&n) => {
+                state.delete_history(n - 1);
+                0
+            }
+            _ => {
+                eprintln!("history: {}: history position out of range", number);
+                1
+            }
+        },
+        CommandAction::HistoryAppend(path) => {
+            // Add the commands run since the last write to what's in the file
+            match merge_history_file(&path, None, state) {
+                Ok(()) => {
+                    state.last_written_count = history.len();
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", path, e);
+                    1
+                }
+            }
+        }
+        CommandAction::Shopt(args) => {
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            let (output, status) = shopt_output(&args, &mut state.options);
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Set(args) => {
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            let (output, status) = set_output(&args, &mut state.options);
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Export(args) => {
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            let (output, status) = export_output(&args, &mut state.env);
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Unset(args) => {
+            for name in args {
+                if name == "PATH" && state.env.contains_key("PATH") {
+                    eprintln!(
+                        "unset: warning: PATH is now unset; external commands may not be found"
+                    );
+                }
+                // Unsetting an undefined variable is a silent no-op
+                state.env.remove(&name);
+            }
+            0
+        }
+        CommandAction::Assign(assignments) => {
+            state.env.extend(assignments);
+            0
+        }
+        CommandAction::WithEnv(assignments, action) => {
+            let saved: Vec<(String, Option<String>)> = assignments
+                .iter()
+                .map(|(name, _)| (name.clone(), state.env.get(name).cloned()))
+                .collect();
+            state.env.extend(assignments);
+            let result = run_action(input, *action, redirection, state);
+            // Put back what the variables were before the builtin
+            for (name, value) in saved {
+                match value {
+                    Some(value) => state.env.insert(name, value),
+                    None => state.env.remove(&name),
+                };
+            }
+            return result;
+        }
+        CommandAction::Alias(args) => handle_alias(&args, state),
+        CommandAction::Test(tokens) => run_test(&tokens),
+        CommandAction::Rehash => {
+            rehash(state);
+            0
+        }
+        CommandAction::Trap(args) => handle_trap(&args, state),
+        CommandAction::Umask(mask) => {
+            let files = match open_redirections(&redirection, &state.options) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    state.last_status = 1;
+                    return Ok(());
+                }
+            };
+            let (output, status) = umask_output(mask.as_deref());
+
+            if let Some(mut file) = files.stdout {
+                let _ = write!(file, "{}", output);
+            } else {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            }
+            status
+        }
+        CommandAction::Jobs => {
+            reap_jobs(state, true);
+            0
+        }
+        CommandAction::Fg(id) => {
+            let index = match id {
+                Some(id) => state.jobs.iter().position(|job| job.id == id),
+                None => state.jobs.len().checked_sub(1),
+            };
+            match index {
+                Some(index) => {
+                    let job = state.jobs.remove(index);
+                    foreground_job(job, state.interactive)
+                }
+                None => {
+                    eprintln!("fg: job not found");
+                    1
+                }
+            }
+        }
+        CommandAction::Unalias(args) => {
+            let mut status = 0;
+            for name in args {
+                if name == "-a" {
+                    state.aliases.clear();
+                } else if state.aliases.remove(&name).is_none() {
+                    eprintln!("unalias: {}: not found", name);
+                    status = 1;
+                }
+            }
+            status
+        }
+        CommandAction::Source(args) => match args.first() {
+            Some(path) => handle_source(path, state),
+            None => {
+                eprintln!("source: filename argument required");
+                2
+            }
+        },
+    };
+
+    state.last_status = status;
+    Ok(())
+}
+
+/// Run each line of the file at `path` in the current shell, skipping blank
+/// lines and `#` comments
+///
+/// Returns the status of the last command run, or 1 if the file can't be read.
+fn handle_source(path: &str, state: &mut ShellState) -> i32 {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("source: {}", path_error(path, e));
+            return 1;
+        }
+    };
+
+    state.last_status = 0;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(e) = execute_line(line, state) {
+            eprintln!("source: {}", e);
+        }
+        if state.errexit_abort {
+            break;
+        }
+    }
+    state.last_status
+}
+
+/// Define aliases from `NAME=value` arguments, print the named ones, or list
+/// all of them when called without arguments
+///
+/// Returns 1 if a named alias doesn't exist, 0 otherwise.
+fn handle_alias(args: &[String], state: &mut ShellState) -> i32 {
+    // Print in a form that can be read back in
+    let format_alias =
+        |name: &str, value: &str| format!("alias {}='{}'", name, value.replace('\'', "'\\''"));
+
+    if args.is_empty() {
+        let mut aliases: Vec<(&String, &String)> = state.aliases.iter().collect();
+        aliases.sort();
+        for (name, value) in aliases {
+            println!("{}", format_alias(name, value));
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            _ => match state.aliases.get(arg) {
+                Some(value) => println!("{}", format_alias(arg, value)),
+                None => {
+                    eprintln!("alias: {}: not found", arg);
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+/// Replace the command word of `input` with its alias definition, repeatedly
+/// while the new command word is also an alias
+///
+/// Each alias is expanded at most once, so `alias ls='ls -F'` and aliases
+/// that refer to each other terminate.
+fn expand_alias(input: &str, aliases: &HashMap<String, String>) -> String {
+    let mut line = input.trim_start().to_string();
+    let mut expanded = Vec::new();
+
+    loop {
+        let end = line.find([' ', '\t']).unwrap_or(line.len());
+        let word = &line[..end];
+        match aliases.get(word) {
+            Some(value) if !expanded.contains(&word.to_string()) => {
+                expanded.push(word.to_string());
+                line = format!("{}{}", value, &line[end..]);
+            }
+            _ => return line,
+        }
+    }
+}
+
+/// Parser: responsible for command dispatch logic
+fn parse_command(input: &str, state: &mut ShellState) -> (CommandAction, Option<Redirection>) {
+    // Expand an alias in the command word before anything else
+    let expanded = expand_alias(input, &state.aliases);
+    let input = expanded.as_str();
+
+    // First check if it's an AI command (starts with !, or !? to explain)
+    let trimmed = input.trim();
+    if let Some(command) = trimmed.strip_prefix("!?") {
+        let tokens: Vec<String> = command.split_whitespace().map(|s| s.to_string()).collect();
+        return (CommandAction::AiExplain(tokens), None);
+    }
+    if let Some(rest) = trimmed.strip_prefix('!') {
+        // Extract all content after ! as AI prompt, up to a `|` starting a
+        // pipeline for the generated command's output (the prompt is plain
+        // language, so its quotes can't be trusted, e.g. "don't")
+        let (prompt, pipeline) = match rest.split_once('|') {
+            Some((prompt, pipeline)) if !pipeline.trim().is_empty() => {
+                (prompt, Some(pipeline.trim().to_string()))
+            }
+            _ => (rest, None),
+        };
+        let prompt_tokens: Vec<String> = prompt.split_whitespace().map(|s| s.to_string()).collect();
+        return (CommandAction::Ai(prompt_tokens, pipeline), None);
+    }
+
+    // A trailing `&` runs the command in the background
+    let (input, background) = strip_background(input);
+
+    // First check if there's a pipeline
+    let pipeline_parts = parse_pipeline(input);
+
+    if pipeline_parts.len() > 1 {
+        // Jobs are single processes, so a whole pipeline can't be one
+        if background {
+            eprintln!("{}: pipelines can't run in the background", input);
+            return (CommandAction::Failed(1), None);
+        }
+
+        // Has pipeline, parse each part
+        let mut commands = Vec::new();
+        state.expansion_error = false;
+
+        for part in pipeline_parts {
+            let (command_part, mut redirection) = parse_redirection(&part, &mut state.heredocs);
+            if !expand_redirection(&mut redirection, state) {
+                return (CommandAction::Failed(1), None);
+            }
+            let mut tokens = match parse_args(&command_part, state) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return (CommandAction::Failed(2), None);
+                }
+            };
+            let assignments = take_assignments(&mut tokens);
+
+            // A stage of only assignments is kept as an empty command
+            let command = if tokens.is_empty() {
+                String::new()
+            } else {
+                tokens.remove(0)
+            };
+            commands.push((command, tokens, redirection, assignments));
+        }
+
+        // A failed expansion in any stage stops the whole pipeline
+        if state.expansion_error {
+            return (CommandAction::Failed(1), None);
+        }
+
+        return (CommandAction::Pipeline(commands), None);
+    }
+
+    // First check if there are redirection operators
+    let (command_part, mut redirection) = parse_redirection(input, &mut state.heredocs);
+    if !expand_redirection(&mut redirection, state) {
+        return (CommandAction::Failed(1), None);
+    }
+
+    // Parse the entire command line, get command and arguments
+    state.expansion_error = false;
+    let mut tokens = match parse_args(&command_part, state) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{}", e);
+            return (CommandAction::Failed(2), None);
+        }
+    };
+
+    // A failed expansion (e.g. division by zero) stops the command
+    if state.expansion_error {
+        return (CommandAction::Failed(1), None);
+    }
+
+    // Leading `NAME=value` words set variables for the command's environment,
+    // or for the shell when there is no command
+    let mut assignments = take_assignments(&mut tokens);
+
+    if tokens.is_empty() {
+        if !assignments.is_empty() {
+            return (CommandAction::Assign(assignments), redirection);
+        }
+        return (CommandAction::Unknown(String::new()), redirection);
+    }
+
+    let command = &tokens[0];
+    let args: Vec<String> = tokens[1..].to_vec();
+
+    let action = match command.as_str() {
+        "exit" => CommandAction::Exit(args.first().map(|arg| {
+            arg.parse().unwrap_or_else(|_| {
+                eprintln!("exit: {}: numeric argument required", arg);
+                255
+            })
+        })),
+        "echo" => CommandAction::Echo(args),
+        "printf" => CommandAction::Printf(args),
+        "pwd" => CommandAction::Pwd,
+        "type" => CommandAction::Type(args),
+        "which" => CommandAction::Which(args),
+        "cd" => CommandAction::Cd(args),
+        "pushd" => CommandAction::Pushd(args.first().cloned()),
+        "popd" => CommandAction::Popd,
+        "dirs" => CommandAction::Dirs,
+        "shopt" => CommandAction::Shopt(args),
+        "set" => CommandAction::Set(args),
+        "export" => CommandAction::Export(args),
+        "unset" => CommandAction::Unset(args),
+        "source" | "." => CommandAction::Source(args),
+        "alias" => CommandAction::Alias(args),
+        "unalias" => CommandAction::Unalias(args),
+        "test" | "[" => CommandAction::Test(tokens.clone()),
+        "jobs" => CommandAction::Jobs,
+        // Job numbers start at 1, so an unparsable reference matches no job
+        "fg" => CommandAction::Fg(
+            args.first()
+                .map(|arg| arg.trim_start_matches('%').parse().unwrap_or(0)),
+        ),
+        "history" => {
+            // Check if it's -r option (read history from file)
+            if args.first().map(|s| s.as_str()) == Some("-r") {
+                if let Some(path) = args.get(1) {
+                    CommandAction::HistoryRead(path.clone())
+                } else {
+                    // -r option missing file path parameter
+                    CommandAction::Unknown("history".to_string())
+                }
+            } else if args.first().map(|s| s.as_str()) == Some("-w") {
+                // Check if it's -w option (write history to file)
+                if let Some(path) = args.get(1) {
+                    // Optional trailing numbers select a range to write
+                    CommandAction::HistoryWrite(
+                        path.clone(),
+                        HistorySelection::from_args(&args[2..]),
+                    )
+                } else {
+                    // -w option missing file path parameter
+                    CommandAction::Unknown("history".to_string())
+                }
+            } else if args.first().map(|s| s.as_str()) == Some("-a") {
+                // Check if it's -a option (append new history to file)
+                if let Some(path) = args.get(1) {
+                    CommandAction::HistoryAppend(path.clone())
+                } else {
+                    // -a option missing file path parameter
+                    CommandAction::Unknown("history".to_string())
+                }
+            } else if args.first().map(|s| s.as_str()) == Some("-c") {
+                // Check if it's -c option (clear history)
+                CommandAction::HistoryClear
+            } else if args.first().map(|s| s.as_str()) == Some("-d") {
+                // Check if it's -d option (delete one entry)
+                if let Some(number) = args.get(1) {
+                    CommandAction::HistoryDelete(number.clone())
+                } else {
+                    // -d option missing entry number
+                    CommandAction::Unknown("history".to_string())
+                }
+            } else {
+                // Parse optional numeric parameters: last N, or a START END range
+                CommandAction::History(HistorySelection::from_args(&args))
+            }
+        }
+        "rehash" => CommandAction::Rehash,
+        "umask" => CommandAction::Umask(args.first().cloned()),
+        "trap" => CommandAction::Trap(args),
+        "hash" => match args.first().map(|s| s.as_str()) {
+            Some("-r") => CommandAction::Rehash,
+            _ => {
+                eprintln!("hash: usage: hash -r");
+                CommandAction::Failed(2)
+            }
+        },
+        _ => {
+            // An assignment to PATH makes the cache stale
+            state.executables.follow_path(state.var("PATH"));
+
+            // A path like `./script` or `/bin/ls` runs that file, without PATH
+            let has_path = command.contains('/');
+
+            // A program installed since the cache was built: pick it up
+            if !has_path
+                && !state.executables.contains(command)
+                && find_command_in_path(command, state.var("PATH")).is_some()
+            {
+                rehash(state);
+            }
+
+            // Check if in preloaded external command cache
+            if !has_path && !state.executables.contains(command) {
+                CommandAction::Unknown(command.to_string())
+            } else if background {
+                CommandAction::Background(
+                    command.to_string(),
+                    args,
+                    std::mem::take(&mut assignments),
+                )
+            } else {
+                CommandAction::External(command.to_string(), args, std::mem::take(&mut assignments))
+            }
+        }
+    };
+
+    // What's left are the assignments for a builtin
+    if !assignments.is_empty() {
+        return (
+            CommandAction::WithEnv(assignments, Box::new(action)),
+            redirection,
+        );
+    }
+    (action, redirection)
+}
+
+/// Remove the leading `NAME=value` words from `tokens`, returning them as
+/// names and values
+fn take_assignments(tokens: &mut Vec<String>) -> Vec<(String, String)> {
+    let assignment_count = tokens
+        .iter()
+        .take_while(|token| {
+            token
+                .split_once('=')
+                .is_some_and(|(name, _)| is_valid_variable_name(name))
+        })
+        .count();
+    tokens
+        .drain(..assignment_count)
+        .filter_map(|token| {
+            let (name, value) = token.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Drop a trailing comment: an unquoted `#` that starts a word, to end of line
+fn strip_comment(input: &str) -> &str {
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut word_start = true;
+    let mut chars = input.char_indices();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                chars.next(); // Skip escaped character
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '#' if word_start && !in_single_quote && !in_double_quote => return &input[..i],
+            _ => {}
+        }
+        // A word also starts after an operator, as in `ls;#comment`
+        word_start = ch.is_whitespace() || matches!(ch, ';' | '&' | '|');
+    }
+    input
+}
+
+/// Split a line on `&&`, `||`, `;` and `&`, ignoring operators inside quotes,
+/// after a backslash and inside command substitutions
+///
+/// Each command is returned trimmed along with the connector before it.
+fn parse_command_list(input: &str) -> Vec<(Connector, String)> {
+    let mut list = Vec::new();
+    let mut connector = Connector::Always;
+    let mut current = String::new();
+    let mut chars = input.chars().peekable();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+    let mut subst_depth = 0;
+    let mut in_backtick = false;
+
+    while let Some(ch) = chars.next() {
+        if escaped {
+            current.push(ch);
+            escaped = false;
+            continue;
+        }
+
+        let unquoted = !in_single_quote && !in_double_quote && subst_depth == 0 && !in_backtick;
+        match ch {
+            '\\' if !in_single_quote => {
+                escaped = true;
+                current.push(ch);
+            }
+            '$' if !in_single_quote && chars.peek() == Some(&'(') => {
+                subst_depth += 1;
+                current.push(ch);
+                current.push(chars.next().unwrap()); // Consume '('
+            }
+            // Process substitution, <(...) or >(...)
+            '<' | '>' if !in_single_quote && !in_double_quote && chars.peek() == Some(&'(') => {
+                subst_depth += 1;
+                current.push(ch);
+                current.push(chars.next().unwrap()); // Consume '('
+            }
+            '(' if !in_single_quote && subst_depth > 0 => {
+                subst_depth += 1;
+                current.push(ch);
+            }
+            ')' if !in_single_quote && subst_depth > 0 => {
+                subst_depth -= 1;
+                current.push(ch);
+            }
+            '`' if !in_single_quote => {
+                in_backtick = !in_backtick;
+                current.push(ch);
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+                current.push(ch);
+            }
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+                current.push(ch);
+            }
+            '&' | '|' if unquoted && chars.peek() == Some(&ch) => {
+                chars.next(); // Consume the second '&' or '|'
+                list.push((connector, current.trim().to_string()));
+                current.clear();
+                connector = if ch == '&' {
+                    Connector::And
+                } else {
+                    Connector::Or
+                };
+            }
+            ';' if unquoted => {
+                list.push((connector, current.trim().to_string()));
+                current.clear();
+                connector = Connector::Always;
+            }
+            '&' if unquoted && !current.ends_with('>') && chars.peek() != Some(&'>') => {
+                // A lone `&` ends a background command; keep it for `parse_command`
+                current.push(ch);
+                list.push((connector, current.trim().to_string()));
+                current.clear();
+                connector = Connector::Always;
+            }
+            _ => {
+                current.push(ch);
+            }
+        }
+    }
+
+    // A trailing `;` doesn't start another command
+    if !current.trim().is_empty() || connector != Connector::Always || list.is_empty() {
+        list.push((connector, current.trim().to_string()));
+    }
+    list
+}
+
+/// Remove a trailing background `&` from a command, reporting whether it had one
+///
+/// `&&`, an escaped `\&` and the `&` of a `>&` redirection don't count.
+fn strip_background(input: &str) -> (&str, bool) {
+    let trimmed = input.trim_end();
+    match trimmed.strip_suffix('&') {
+        Some(rest) if !rest.ends_with(['&', '\\', '>']) => (rest.trim_end(), true),
+        _ => (input, false),
+    }
+}
+
+/// Parse pipeline: split commands by | but ignore | inside quotes
+fn parse_pipeline(input: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut chars = input.chars().peekable();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+    // Nesting depth of $(...) and whether inside `...`: pipes there belong to the substitution
+    let mut subst_depth = 0;
+    let mut in_backtick = false;
+
+    while let Some(ch) = chars.next() {
+        if escaped {
+            current.push(ch);
+            escaped = false;
+            continue;
+        }
+
+        match ch {
+            '\\' if !in_single_quote => {
+                escaped = true;
+                current.push(ch);
+            }
+            '$' if !in_single_quote && chars.peek() == Some(&'(') => {
+                subst_depth += 1;
+                current.push(ch);
+                current.push(chars.next().unwrap()); // Consume '('
+            }
+            // Process substitution, <(...) or >(...)
+            '<' | '>' if !in_single_quote && !in_double_quote && chars.peek() == Some(&'(') => {
+                subst_depth += 1;
+                current.push(ch);
+                current.push(chars.next().unwrap()); // Consume '('
+            }
+            '(' if !in_single_quote && subst_depth > 0 => {
+                subst_depth += 1;
+                current.push(ch);
+            }
+            ')' if !in_single_quote && subst_depth > 0 => {
+                subst_depth -= 1;
+                current.push(ch);
+            }
+            '`' if !in_single_quote => {
+                in_backtick = !in_backtick;
+                current.push(ch);
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+                current.push(ch);
+            }
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+                current.push(ch);
+            }
+            // `>|` is a redirection, not a pipe
+            '|' if !in_single_quote
+                && !in_double_quote
+                && subst_depth == 0
+                && !in_backtick
+                && !current.ends_with('>') =>
+            {
+                // Found pipe, save current command
+                if !current.trim().is_empty() {
+                    commands.push(current.trim().to_string());
+                    current.clear();
+                }
+            }
+            _ => {
+                current.push(ch);
+            }
+        }
+    }
+
+    // Add the last command
+    if !current.trim().is_empty() {
+        commands.push(current.trim().to_string());
+    }
+
+    // If no pipeline, return single command
+    if commands.is_empty() {
+        vec![input.to_string()]
+    } else {
+        commands
+    }
+}
+
+/// Parse redirection operators, return command part and redirection info
+///
+/// Each `<<` here-document takes the next body from `heredocs`.
+fn parse_redirection(
+    input: &str,
+    heredocs: &mut VecDeque<String>,
+) -> (String, Option<Redirection>) {
+    let mut chars = input.chars().peekable();
+    let mut command_part = String::new();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+    let mut outputs: Vec<OutputRedirect> = Vec::new();
+    let mut stdin_file: Option<String> = None;
+    let mut heredoc: Option<String> = None;
+    let mut heredoc_expand = false;
+    let mut here_string: Option<String> = None;
+    // Nesting depth of $(...) and whether inside `...`: redirections there belong to the substitution
+    let mut subst_depth = 0;
+    let mut in_backtick = false;
+
+    while let Some(&ch) = chars.peek() {
+        let unquoted = !in_single_quote && !in_double_quote && subst_depth == 0 && !in_backtick;
+
+        // Handle quote state
+        if !escaped {
+            match ch {
+                '\\' if !in_single_quote => {
+                    escaped = true;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                '\'' if !in_double_quote => {
+                    in_single_quote = !in_single_quote;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                '"' if !in_single_quote => {
+                    in_double_quote = !in_double_quote;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                '$' if !in_single_quote => {
+                    command_part.push(chars.next().unwrap());
+                    if chars.peek() == Some(&'(') {
+                        subst_depth += 1;
+                        command_part.push(chars.next().unwrap());
+                    }
+                    continue;
+                }
+                '<' | '>' if unquoted && chars.clone().nth(1) == Some('(') => {
+                    // Process substitution: left for `parse_args`
+                    subst_depth += 1;
+                    command_part.push(chars.next().unwrap());
+                    command_part.push(chars.next().unwrap()); // Consume '('
+                    continue;
+                }
+                '(' if !in_single_quote && subst_depth > 0 => {
+                    subst_depth += 1;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                ')' if !in_single_quote && subst_depth > 0 => {
+                    subst_depth -= 1;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                '`' if !in_single_quote => {
+                    in_backtick = !in_backtick;
+                    command_part.push(chars.next().unwrap());
+                    continue;
+                }
+                '>' if unquoted => {
+                    // Found redirection operator
+                    chars.next(); // Consume '>'
+                    outputs.extend(parse_output_redirect(&mut chars, 1));
+                    continue;
+                }
+                '&' if unquoted && chars.clone().nth(1) == Some('>') => {
+                    // `&>file` / `&>>file`: stdout to the file, stderr sharing it
+                    chars.next(); // Consume '&'
+                    chars.next(); // Consume '>'
+                    if let Some(file @ OutputRedirect::File(..)) =
+                        parse_output_redirect(&mut chars, 1)
+                    {
+                        outputs.push(file);
+                        outputs.push(OutputRedirect::Dup(2, 1));
+                    }
+                    continue;
+                }
+                '<' if unquoted => {
+                    // Found input redirection operator
+                    chars.next(); // Consume '<'
+
+                    if chars.peek() == Some(&'<') {
+                        chars.next(); // Consume second '<'
+                        stdin_file = None;
+
+                        if chars.peek() == Some(&'<') {
+                            // Here-string: the next word is the input
+                            chars.next(); // Consume third '<'
+                            while chars.peek() == Some(&' ') {
+                                chars.next();
+                            }
+                            here_string = Some(parse_word(&mut chars));
+                            heredoc = None;
+                            continue;
+                        }
+
+                        // Here-document: the body was read along with the line
+                        let (_, _, quoted) = parse_heredoc_delimiter(&mut chars);
+                        heredoc = Some(heredocs.pop_front().unwrap_or_default());
+                        heredoc_expand = !quoted;
+                        here_string = None;
+                        continue;
+                    }
+
+                    // Skip spaces
+                    while chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+
+                    // Get input filename
+                    let file = parse_filename(&mut chars);
+
+                    if !file.is_empty() {
+                        stdin_file = Some(file);
+                        heredoc = None;
+                        here_string = None;
+                    }
+                    continue;
+                }
+                '0' if unquoted => {
+                    // Check if it's "0<" form (explicit stdin redirection)
+                    let mut temp_chars = chars.clone();
+                    temp_chars.next(); // Skip '0'
+                    if temp_chars.peek() == Some(&'<') {
+                        chars.next(); // Consume '0'
+                        chars.next(); // Consume '<'
+
+                        // Skip spaces
+                        while chars.peek() == Some(&' ') {
+                            chars.next();
+                        }
+
+                        // Get input filename
+                        let file = parse_filename(&mut chars);
+
+                        if !file.is_empty() {
+                            stdin_file = Some(file);
+                            heredoc = None;
+                            here_string = None;
+                        }
+                    } else {
+                        command_part.push(chars.next().unwrap());
+                    }
+                    continue;
+                }
+                '1' | '2' if unquoted => {
+                    // Check if it's "1>"/"2>" form (with ">>" or ">&" variants)
+                    let mut temp_chars = chars.clone();
+                    temp_chars.next(); // Skip the digit
+                    if temp_chars.peek() == Some(&'>') {
+                        let fd = if chars.next() == Some('1') { 1 } else { 2 };
+                        chars.next(); // Consume '>'
+                        outputs.extend(parse_output_redirect(&mut chars, fd));
+                    } else {
+                        command_part.push(chars.next().unwrap());
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        escaped = false;
+        command_part.push(chars.next().unwrap());
+    }
+
+    // Build redirection info
+    let redirection = if !outputs.is_empty()
+        || stdin_file.is_some()
+        || heredoc.is_some()
+        || here_string.is_some()
+    {
+        Some(Redirection {
+            outputs,
+            stdin_file,
+            heredoc,
+            heredoc_expand,
+            here_string,
+        })
+    } else {
+        None
+    };
+
+    (command_part.trim_end().to_string(), redirection)
+}
+
+/// Parse the rest of an output redirection for descriptor `fd` after its `>`
+///
+/// Handles `>>file` (append), `>&m` (duplicate descriptor m) and `>file`;
+/// returns `None` if no target follows.
+fn parse_output_redirect(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    fd: i32,
+) -> Option<OutputRedirect> {
+    // Check for append mode '>>' or the forced overwrite '>|'
+    let mode = match chars.peek() {
+        Some('>') => WriteMode::Append,
+        Some('|') => WriteMode::Clobber,
+        _ => WriteMode::Truncate,
+    };
+    if mode != WriteMode::Truncate {
+        chars.next(); // Consume second '>' or '|'
+    }
+
+    // Check if it's descriptor duplication '>&1' or '>&2'
+    if mode == WriteMode::Truncate && chars.peek() == Some(&'&') {
+        let mut temp = chars.clone();
+        temp.next(); // Skip '&'
+        if let Some(target @ ('1' | '2')) = temp.peek().copied() {
+            chars.next(); // Consume '&'
+            chars.next(); // Consume the digit
+            return Some(OutputRedirect::Dup(fd, if target == '1' { 1 } else { 2 }));
+        }
+    }
+
+    // Skip spaces
+    while chars.peek() == Some(&' ') {
+        chars.next();
+    }
+
+    // Get output filename (read until next redirect or end)
+    let file = parse_filename(chars);
+    if file.is_empty() {
+        None
+    } else {
+        Some(OutputRedirect::File(fd, file, mode))
+    }
+}
+
+/// Parse filename from character iterator (until unquoted space, redirect or end)
+///
+/// Quotes, escapes and `$(...)` are kept as typed, for
+/// `expand_redirection_targets` to interpret.
+fn parse_filename(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut filename = String::new();
+
+    // A process substitution target is kept whole
+    if let Some(&ch @ ('<' | '>')) = chars.peek()
+        && chars.clone().nth(1) == Some('(')
+    {
+        chars.next(); // Consume '<' or '>'
+        chars.next(); // Consume '('
+        return format!("{}({})", ch, read_substitution_body(chars));
+    }
+
+    let mut quote: Option<char> = None;
+    while let Some(&ch) = chars.peek() {
+        match (quote, ch) {
+            // Stop condition: space or redirection operator
+            (None, ' ' | '>' | '<') => break,
+            (None, '0' | '1' | '2') => {
+                // Check if it's the start of a redirection operator
+                let operator = if ch == '0' { '<' } else { '>' };
+                if chars.clone().nth(1) == Some(operator) {
+                    break;
+                }
+            }
+            (None, '\'' | '"') => quote = Some(ch),
+            (Some(q), _) if ch == q => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                // Keep an escaped character, so `my\ file` stays one name
+                filename.push(ch);
+                chars.next();
+                if chars.peek().is_none() {
+                    break;
+                }
+            }
+            (_, '$') if chars.clone().nth(1) == Some('(') => {
+                chars.next(); // Consume '$'
+                chars.next(); // Consume '('
+                filename.push_str("$(");
+                filename.push_str(&read_substitution_body(chars));
+                filename.push(')');
+                continue;
+            }
+            _ => {}
+        }
+
+        filename.push(chars.next().unwrap());
+    }
+
+    filename.trim().to_string()
+}
+
+/// Expand a command's redirections: their targets, a here-string and an
+/// unquoted here-document
+///
+/// Returns false (after reporting it) if a target is ambiguous or has an
+/// unclosed quote.
+fn expand_redirection(redirection: &mut Option<Redirection>, state: &mut ShellState) -> bool {
+    let Some(redir) = redirection.as_mut() else {
+        return true;
+    };
+
+    // Expand redirection targets, e.g. `> "$dir/out file"` or `< <(cmd)`
+    if let Err(e) = expand_redirection_targets(redir, state) {
+        eprintln!("{}", e);
+        return false;
+    }
+
+    // A here-string is expanded like an argument, and ends with a newline
+    if let Some(word) = redir.here_string.take() {
+        match expand_here_string(&word, state) {
+            Ok(text) => redir.heredoc = Some(format!("{}\n", text)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+        redir.heredoc_expand = false;
+    }
+
+    // Expand variables and substitutions in an unquoted here-document
+    if redir.heredoc_expand
+        && let Some(body) = redir.heredoc.as_mut()
+    {
+        *body = expand_heredoc_body(body, state);
+    }
+
+    true
+}
+
+/// Expand redirection targets like command arguments: quotes, variables,
+/// `~`, substitutions and globs
+///
+/// A target must expand to exactly one word; otherwise it's an "ambiguous
+/// redirect" error.
+fn expand_redirection_targets(
+    redir: &mut Redirection,
+    state: &mut ShellState,
+) -> Result<(), String> {
+    let targets = redir
+        .outputs
+        .iter_mut()
+        .filter_map(|output| match output {
+            OutputRedirect::File(_, path, _) => Some(path),
+            OutputRedirect::Dup(..) => None,
+        })
+        .chain(redir.stdin_file.as_mut());
+
+    for path in targets {
+        let mut words = parse_args(path, state)?;
+        if words.len() != 1 {
+            return Err(format!("{}: ambiguous redirect", path));
+        }
+        *path = words.remove(0);
+    }
+    Ok(())
+}
+
+/// Read one shell word as typed, up to unquoted whitespace, keeping its
+/// quotes and escapes for `parse_args` to interpret
+fn parse_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+
+    while let Some(&ch) = chars.peek() {
+        match (quote, ch) {
+            (None, ' ' | '\t') => break,
+            (None, '\'' | '"') => quote = Some(ch),
+            (Some(q), _) if ch == q => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                word.push(ch);
+                chars.next();
+                match chars.peek() {
+                    Some(&next) => word.push(next),
+                    None => break,
+                }
+            }
+            (_, '$') if chars.clone().nth(1) == Some('(') => {
+                // Keep a substitution whole, spaces and all
+                chars.next(); // Consume '$'
+                chars.next(); // Consume '('
+                word.push_str("$(");
+                word.push_str(&read_substitution_body(chars));
+                word.push(')');
+                continue;
+            }
+            _ => {}
+        }
+        word.push(ch);
+        chars.next();
+    }
+
+    word
+}
+
+/// Parse a here-document delimiter after `<<` has been consumed
+///
+/// Returns the delimiter with quotes removed, whether it was `<<-` (strip
+/// leading tabs) and whether any part was quoted (no expansion of the body).
+fn parse_heredoc_delimiter(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> (String, bool, bool) {
+    let strip_tabs = chars.peek() == Some(&'-');
+    if strip_tabs {
+        chars.next(); // Consume '-'
+    }
+
+    // Skip spaces
+    while chars.peek() == Some(&' ') {
+        chars.next();
+    }
+
+    let mut delimiter = String::new();
+    let mut quoted = false;
+    let mut quote: Option<char> = None;
+
+    while let Some(&ch) = chars.peek() {
+        match (quote, ch) {
+            (Some(q), _) if ch == q => quote = None,
+            (Some(_), _) => delimiter.push(ch),
+            (None, '\'' | '"') => {
+                quote = Some(ch);
+                quoted = true;
+            }
+            (None, '\\') => {
+                quoted = true;
+                chars.next();
+                if let Some(&next) = chars.peek() {
+                    delimiter.push(next);
+                } else {
+                    break;
+                }
+            }
+            (None, ' ' | ';' | '|' | '&' | '<' | '>') => break,
+            (None, _) => delimiter.push(ch),
+        }
+        chars.next();
+    }
+
+    (delimiter, strip_tabs, quoted)
+}
+
+/// Find the here-documents started on a line, in order, as their delimiter
+/// and whether leading tabs are stripped (`<<-`)
+fn find_heredocs(line: &str) -> Vec<(String, bool)> {
+    let mut heredocs = Vec::new();
+    let mut chars = line.chars().peekable();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    // Nesting depth of $(...) and $((...)): `<<` there isn't a here-document of this line
+    let mut subst_depth = 0;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                chars.next(); // Skip escaped character
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '$' | '<' | '>' if !in_single_quote && chars.peek() == Some(&'(') => {
+                chars.next(); // Consume '('
+                subst_depth += 1;
+            }
+            '(' if !in_single_quote && subst_depth > 0 => subst_depth += 1,
+            ')' if !in_single_quote && subst_depth > 0 => subst_depth -= 1,
+            '<' if !in_single_quote
+                && !in_double_quote
+                && subst_depth == 0
+                && chars.peek() == Some(&'<') =>
+            {
+                chars.next(); // Consume second '<'
+                // `<<<` is a here-string, with no body to read
+                if chars.peek() == Some(&'<') {
+                    chars.next();
+                    continue;
+                }
+                let (delimiter, strip_tabs, _) = parse_heredoc_delimiter(&mut chars);
+                heredocs.push((delimiter, strip_tabs));
+            }
+            _ => {}
+        }
+    }
+
+    heredocs
+}
+
+/// Why an input line continues onto the next one
+enum Continuation {
+    /// Ends in an unescaped `\`, which is dropped along with the newline
+    Backslash,
+    /// Has a quote that isn't closed yet; the newline is kept
+    Quote(char),
+}
+
+/// Check whether a line is incomplete and needs another line of input
+///
+/// Quotes inside `$(...)` are matched separately from the ones around it, so
+/// `"$(echo "it's")"` is complete.
+fn line_continuation(line: &str) -> Option<Continuation> {
+    let mut quote: Option<char> = None;
+    // Quote state outside each `(` we're in
+    let mut outer: Vec<Option<char>> = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            // The escaped character is skipped; nothing after means a continuation
+            (_, '\\') if chars.next().is_none() => return Some(Continuation::Backslash),
+            (Some('"'), '"') => quote = None,
+            (Some('"'), '$') if chars.peek() == Some(&'(') => {
+                chars.next(); // Consume '('
+                outer.push(quote.take());
+            }
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, '(') => outer.push(None),
+            (None, ')') => {
+                if let Some(outer_quote) = outer.pop() {
+                    quote = outer_quote;
+                }
+            }
+            _ => {}
+        }
+    }
+    quote.map(Continuation::Quote)
+}
+
+/// Whether a line is an AI request (`!words` or `!?command`) rather than
+/// shell syntax, as opposed to `!!`, `!n` and `!-n`
+fn is_ai_request(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix('!')
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|c| !(c == '!' || c == '-' || c.is_ascii_digit() || c.is_whitespace()))
+}
+
+/// Read `PS2` continuation lines until the command is complete
+///
+/// An AI request is plain language, so only a trailing `\` continues it: an
+/// apostrophe as in `!what's using port 80` isn't an open quote.
+///
+/// Returns `None` if input is interrupted, or ends inside a quote.
+fn read_continuation_lines(mut line: String, state: &mut ShellState) -> Option<String> {
+    while let Some(continuation) = line_continuation(strip_comment(&line)) {
+        if matches!(continuation, Continuation::Quote(_)) && is_ai_request(&line) {
+            break;
+        }
+        match continuation {
+            Continuation::Backslash => {
+                line.pop();
+            }
+            Continuation::Quote(_) => line.push('\n'),
+        }
+
+        let prompt = state.var("PS2").unwrap_or("> ").to_string();
+        match state.editor.readline(&prompt) {
+            Ok(next) => line.push_str(&next),
+            Err(ReadlineError::Eof) => {
+                if let Continuation::Quote(quote) = continuation {
+                    eprintln!("unexpected EOF while looking for matching `{}'", quote);
+                    state.last_status = 2;
+                    return None;
+                }
+                break;
+            }
+            Err(_) => return None,
+        }
+    }
+    Some(line)
+}
+
+/// Read the bodies of the here-documents started on `line` from the editor,
+/// prompting with `$PS2`, and queue them for `parse_redirection`
+fn read_heredoc_bodies(line: &str, state: &mut ShellState) {
+    state.heredocs.clear();
+
+    for (delimiter, strip_tabs) in find_heredocs(strip_comment(line)) {
+        let prompt = state.var("PS2").unwrap_or("> ").to_string();
+        let mut body = String::new();
+
+        loop {
+            match state.editor.readline(&prompt) {
+                Ok(input) => {
+                    let input = if strip_tabs {
+                        input.trim_start_matches('\t')
+                    } else {
+                        &input
+                    };
+                    if input == delimiter {
+                        break;
+                    }
+                    body.push_str(input);
+                    body.push('\n');
+                }
+                Err(_) => {
+                    eprintln!(
+                        "warning: here-document delimited by end-of-file (wanted `{}')",
+                        delimiter
+                    );
+                    break;
+                }
+            }
+        }
+
+        state.heredocs.push_back(body);
+    }
+}
+
+/// Expand `$NAME`, `${NAME}`, `$?`, `$$`, `$!`, `$(...)` and backticks in a
+/// here-document body; a backslash keeps a following `$`, `` ` `` or `\` literal
+fn expand_heredoc_body(body: &str, state: &mut ShellState) -> String {
+    let mut result = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if matches!(chars.peek(), Some('$' | '`' | '\\')) => {
+                result.push(chars.next().unwrap());
+            }
+            '$' if starts_arithmetic(&chars) => {
+                result.push_str(&expand_arithmetic(&mut chars, state));
+            }
+            '$' if chars.peek() == Some(&'(') => {
+                chars.next(); // Consume '('
+                let command = read_substitution_body(&mut chars);
+                result.push_str(&capture_command_output(&command, state));
+            }
+            '$' => result.push_str(&expand_variable(&mut chars, state)),
+            '`' => {
+                let command: String = chars.by_ref().take_while(|&c| c != '`').collect();
+                result.push_str(&capture_command_output(&command, state));
+            }
+            _ => result.push(ch),
+        }
+    }
+
+    result
+}
+
+/// Create a pipe whose read end yields `body`, for feeding a here-document
+/// to a command's stdin
+#[cfg(unix)]
+fn heredoc_pipe(body: String) -> io::Result<File> {
+    // Close-on-exec, so a command started while the writer thread is still
+    // running doesn't inherit the write end and wait forever for EOF
+    let mut pipe_fds = [0; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for fd in pipe_fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    let reader = unsafe { File::from_raw_fd(pipe_fds[0]) };
+    let mut writer = unsafe { File::from_raw_fd(pipe_fds[1]) };
+
+    // Write from a thread so bodies larger than the pipe buffer don't block
+    std::thread::spawn(move || {
+        let _ = writer.write_all(body.as_bytes());
+    });
+
+    Ok(reader)
+}
+
+/// Write `body` to a temporary file and open it for reading, for feeding a
+/// here-document to a command's stdin (as bash does)
+///
+/// The file is removed at once; the open handle keeps its contents readable.
+#[cfg(not(unix))]
+fn heredoc_pipe(body: String) -> io::Result<File> {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let path = env::temp_dir().join(format!(
+        "shell-ai-heredoc-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, body)?;
+    let file = File::open(&path);
+    let _ = fs::remove_file(&path);
+    file
+}
+
+/// Describe each name for the type builtin: `type [-a] name...`
+///
+/// With `-a`, every match is listed: the builtin, then each executable of
+/// that name in `PATH` order. Unknown names are reported on stderr.
+///
+/// Returns the output and the exit status: 0 if all were found, 1 if not.
+fn type_output(args: &[String], path: Option<&str>) -> (String, i32) {
+    let (all, names) = match args.first().map(|s| s.as_str()) {
+        Some("-a") => (true, &args[1..]),
+        _ => (false, args),
+    };
+
+    let mut output = String::new();
+    let mut status = 0;
+    for name in names.iter().filter(|name| !name.is_empty()) {
+        let mut found = Vec::new();
+        if BUILTINS.contains(&name.as_str()) {
+            found.push(format!("{} is a shell builtin\n", name));
+        }
+        if all || found.is_empty() {
+            for executable in find_all_in_path(name, path) {
+                found.push(format!("{} is {}\n", name, executable.display()));
+            }
+        }
+
+        if found.is_empty() {
+            eprintln!("{}: not found", name);
+            status = 1;
+        } else if all {
+            output.extend(found);
+        } else {
+            output.push_str(&found[0]);
+        }
+    }
+    (output, status)
+}
+
+/// Run the export builtin, `export [NAME[=value]...]`, on `env`, returning
+/// its output and exit status
+///
+/// With no arguments, lists every exported variable as `NAME=value`, sorted.
+fn export_output(args: &[String], env: &mut HashMap<String, String>) -> (String, i32) {
+    if args.is_empty() {
+        let mut vars: Vec<(&String, &String)> = env.iter().collect();
+        vars.sort();
+        let listing = vars
+            .into_iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect();
+        return (listing, 0);
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+
+        if !is_valid_variable_name(name) {
+            eprintln!("export: `{}': not a valid identifier", arg);
+            status = 1;
+            continue;
+        }
+
+        // Every variable the shell knows is already exported, so a bare
+        // `export NAME` only has to be accepted
+        if let Some(value) = value {
+            env.insert(name.to_string(), value.to_string());
+        }
+    }
+    (String::new(), status)
+}
+
+/// Check that a string is a valid variable name: letters, digits and
+/// underscores, not starting with a digit
+fn is_valid_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Run the shopt builtin, `shopt [-s|-u] [name...]`, on `options`, returning
+/// its output (the options, when listing them) and exit status
+fn shopt_output(args: &[String], options: &mut ShellOptions) -> (String, i32) {
+    let mut output = String::new();
+    let (setting, names) = match args.first().map(|s| s.as_str()) {
+        Some("-s") => (Some(true), &args[1..]),
+        Some("-u") => (Some(false), &args[1..]),
+        _ => (None, args),
+    };
+
+    // Without names, list every option
+    let names: Vec<&str> = if names.is_empty() {
+        SHOPT_NAMES.to_vec()
+    } else {
+        names.iter().map(|s| s.as_str()).collect()
+    };
+
+    let mut status = 0;
+    for name in names {
+        match options.get_mut(name) {
+            Some(value) => match setting {
+                Some(on) => *value = on,
+                None => output.push_str(&format!(
+                    "{}\t{}\n",
+                    name,
+                    if *value { "on" } else { "off" }
+                )),
+            },
+            None => {
+                eprintln!("shopt: {}: invalid shell option name", name);
+                status = 1;
+            }
+        }
+    }
+    (output, status)
+}
+
+/// Run the set builtin, `set [-e|+e] [-C|+C] [-o|+o [name]]...`, on
+/// `options`, returning its output and exit status
+///
+/// `set -o` lists the options with their state; `set +o` lists them as the
+/// `set` commands that would restore them.
+fn set_output(args: &[String], options: &mut ShellOptions) -> (String, i32) {
+    if args.is_empty() {
+        return set_output(&["-o".to_string()], options);
+    }
+
+    let mut output = String::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let on = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => {
+                eprintln!("set: {}: invalid option", arg);
+                return (output, 2);
+            }
+        };
+
+        for flag in arg[1..].chars() {
+            match flag {
+                'e' => options.errexit = on,
+                'C' => options.noclobber = on,
+                'o' => match args.next() {
+                    Some(name) => match options.set_option_mut(name) {
+                        Some(value) => *value = on,
+                        None => {
+                            eprintln!("set: {}: invalid option name", name);
+                            return (output, 1);
+                        }
+                    },
+                    // Without a name, list the options
+                    None => {
+                        for name in SET_OPTION_NAMES {
+                            let value = *options.set_option_mut(name).unwrap();
+                            let line = if on {
+                                format!("{:<15}\t{}\n", name, if value { "on" } else { "off" })
+                            } else {
+                                format!("set {}o {}\n", if value { '-' } else { '+' }, name)
+                            };
+                            output.push_str(&line);
+                        }
+                    }
+                },
+                _ => {
+                    eprintln!(
+                        "set: {}{}: invalid option",
+                        if on { '-' } else { '+' },
+                        flag
+                    );
+                    return (output, 2);
+                }
+            }
+        }
+    }
+    (output, 0)
+}
+
+/// Run the umask builtin, returning its output and exit status: with no
+/// mask, print the current one in octal; otherwise set it from an octal number
+#[cfg(unix)]
+fn umask_output(mask: Option<&str>) -> (String, i32) {
+    match mask {
+        // Read the mask by setting it, then put it back
+        None => {
+            let current = unsafe { libc::umask(0) };
+            unsafe { libc::umask(current) };
+            (format!("{:04o}\n", current), 0)
+        }
+        Some(mask) => match libc::mode_t::from_str_radix(mask, 8) {
+            Ok(value) if value <= 0o777 => {
+                unsafe { libc::umask(value) };
+                (String::new(), 0)
+            }
+            _ => {
+                eprintln!("umask: {}: octal number out of range", mask);
+                (String::new(), 1)
+            }
+        },
+    }
+}
+
+/// Run the umask builtin, returning its output and exit status; there are no
+/// permission bits for a mask to clear, so it reads as `0000` and can't be set
+#[cfg(not(unix))]
+fn umask_output(mask: Option<&str>) -> (String, i32) {
+    match mask {
+        None => ("0000\n".to_string(), 0),
+        Some(_) => {
+            eprintln!("umask: not supported on this platform");
+            (String::new(), 1)
+        }
+    }
+}
+
+/// Ask the user a y/n question on the terminal, returning true only for y/yes
+fn confirm(question: &str) -> bool {
+    let response = read_answer(question).to_lowercase();
+    response == "y" || response == "yes"
+}
+
+/// Ask a question and read the answer, trimmed (empty if reading fails)
+///
+/// The question goes to stderr, so it isn't mixed into redirected or captured
+/// output. Without a terminal to answer from, the answer is empty rather than
+/// the next line of a script.
+fn read_answer(question: &str) -> String {
+    if !io::stdin().is_terminal() {
+        return String::new();
+    }
+    eprint!("{}", question);
+    let _ = io::stderr().flush();
+
+    let mut response = String::new();
+    if io::stdin().lock().read_line(&mut response).is_err() {
+        return String::new();
+    }
+    response.trim().to_string()
+}
+
+/// Patterns flagging an AI-generated command as destructive, unless
+/// `SHELL_AI_DANGER_PATTERNS` gives a comma-separated list instead
+const DEFAULT_DANGER_PATTERNS: [&str; 9] = [
+    "rm -rf",
+    "find -delete",
+    "mkfs",
+    "dd of=",
+    ":(){ :|:& };:",
+    "> /dev/sd",
+    ">/dev/sd",
+    "> /dev/nvme",
+    ">/dev/nvme",
+];
+
+/// The first danger pattern `command` matches, if any
+///
+/// A pattern matches when each of its words starts a word of the command, in
+/// order, so `dd of=` catches `dd if=/dev/zero of=/dev/sda`. An `rm` pattern
+/// like `rm -rf` matches by option letters instead (see `rm_has_flags`).
+fn dangerous_pattern(command: &str, env: &HashMap<String, String>) -> Option<String> {
+    let patterns: Vec<String> = match env.get("SHELL_AI_DANGER_PATTERNS") {
+        Some(list) => list
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect(),
+        None => DEFAULT_DANGER_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    };
+    let words: Vec<&str> = command.split_whitespace().collect();
+    patterns.into_iter().find(|pattern| {
+        if let Some(flags) = pattern.strip_prefix("rm -")
+            && !flags.starts_with('-')
+            && !flags.contains(char::is_whitespace)
+        {
+            return rm_has_flags(&words, flags);
+        }
+        let mut rest = words.iter();
+        pattern
+            .split_whitespace()
+            .all(|needle| rest.any(|word| word.starts_with(needle)))
+    })
+}
+
+/// Whether any `rm` in `words` is given every option letter in `flags`, however
+/// they're spelled: `-rf`, `-r -f`, `-Rf`, `-vfr` or `--recursive --force`
+fn rm_has_flags(words: &[&str], flags: &str) -> bool {
+    let normalize = |c: char| if c == 'R' { 'r' } else { c };
+    let is_rm = |word: &&str| word.rsplit('/').next() == Some("rm");
+    words
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| is_rm(word))
+        .any(|(i, _)| {
+            let mut given = HashSet::new();
+            // The options of this `rm`, up to the end of its command
+            let arguments = words[i + 1..]
+                .iter()
+                .take_while(|word| !matches!(**word, ";" | "&&" | "||" | "|" | "&"));
+            for word in arguments {
+                match *word {
+                    "--" => break,
+                    "--recursive" => {
+                        given.insert('r');
+                    }
+                    "--force" => {
+                        given.insert('f');
+                    }
+                    _ if word.starts_with('-') && !word.starts_with("--") => {
+                        given.extend(word[1..].chars().map(normalize));
+                    }
+                    _ => {}
+                }
+            }
+            flags
+                .chars()
+                .map(normalize)
+                .all(|flag| given.contains(&flag))
+        })
+}
+
+/// Open a redirection target for writing, in append or truncate mode
+///
+/// With the `confirm_overwrite` option set, truncating an existing file asks first
+/// and a refusal is returned as an error so the command is aborted.
+fn open_redirect_file(path: &str, mode: WriteMode, options: &ShellOptions) -> io::Result<File> {
+    // Like bash, noclobber (and the overwrite prompt) only protect regular
+    // files, so `>/dev/null` still works
+    let exists = fs::metadata(path).is_ok_and(|m| m.is_file());
+    let result = if mode == WriteMode::Append {
+        OpenOptions::new().create(true).append(true).open(path)
+    } else if mode == WriteMode::Clobber {
+        File::create(path)
+    } else if options.noclobber && exists {
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "cannot overwrite existing file",
+        ))
+    } else if options.confirm_overwrite && exists && !io::stdin().is_terminal() {
+        // Nobody can answer the prompt, so refuse as noclobber does
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "cannot overwrite existing file (no terminal to confirm)",
+        ))
+    } else if options.confirm_overwrite
+        && exists
+        && !confirm(&format!("overwrite {}? (y/n): ", path))
+    {
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "not overwritten",
+        ))
+    } else {
+        File::create(path)
+    };
+
+    result.map_err(|e| path_error(path, e))
+}
+
+/// Report a command that couldn't be started, e.g. `cmd: Permission denied`,
+/// returning its exit status like bash: 127 if it (or its interpreter) is
+/// missing, 126 if it couldn't be executed
+fn spawn_error_status(command: &str, e: io::Error) -> i32 {
+    let status = if e.kind() == io::ErrorKind::NotFound {
+        127
+    } else {
+        126
+    };
+    eprintln!("{}", path_error(command, e));
+    status
+}
+
+/// Report a pipeline stage that couldn't be started, returning its exit status
+///
+/// Stages aren't looked up in `PATH` beforehand, so a missing one is reported
+/// as an unknown command.
+fn stage_spawn_error_status(command: &str, e: io::Error) -> i32 {
+    if e.kind() == io::ErrorKind::NotFound {
+        eprintln!("{}: command not found", command);
+        127
+    } else {
+        spawn_error_status(command, e)
+    }
+}
+
+/// Prefix an error opening a file with its path, dropping the
+/// `(os error N)` suffix so it reads like `file: No such file or directory`
+fn path_error(path: &str, e: io::Error) -> io::Error {
+    let message = e.to_string();
+    let message = match message.find(" (os error") {
+        Some(end) => &message[..end],
+        None => &message,
+    };
+    io::Error::new(e.kind(), format!("{}: {}", path, message))
+}
+
+/// Open all files named by a command's redirections
+fn open_redirections(
+    redirection: &Option<Redirection>,
+    options: &ShellOptions,
+) -> io::Result<RedirectFiles> {
+    let mut files = RedirectFiles {
+        stdin: None,
+        stdout: None,
+        stderr: None,
+    };
+
+    if let Some(redir) = redirection {
+        if let Some(stdin_file) = &redir.stdin_file {
+            files.stdin = Some(File::open(stdin_file).map_err(|e| path_error(stdin_file, e))?);
+        }
+        if let Some(body) = &redir.heredoc {
+            files.stdin = Some(heredoc_pipe(body.clone())?);
+        }
+        for output in &redir.outputs {
+            match output {
+                OutputRedirect::File(fd, path, mode) => {
+                    let file = open_redirect_file(path, *mode, options)?;
+                    if *fd == 1 {
+                        files.stdout = Some(file);
+                    } else {
+                        files.stderr = Some(file);
+                    }
+                }
+                OutputRedirect::Dup(fd, target) => {
+                    // Copy whatever the target currently points to
+                    let current = if *target == 1 {
+                        &files.stdout
+                    } else {
+                        &files.stderr
+                    };
+                    let file = match current {
+                        Some(file) => file.try_clone()?,
+                        None => dup_fd(*target)?,
+                    };
+                    if *fd == 1 {
+                        files.stdout = Some(file);
+                    } else {
+                        files.stderr = Some(file);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Duplicate one of the shell's own descriptors as a `File`
+#[cfg(unix)]
+fn dup_fd(fd: i32) -> io::Result<File> {
+    let new_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if new_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(new_fd) })
+}
+
+/// Duplicate the shell's own stdout (1) or stderr (2) handle as a `File`
+#[cfg(windows)]
+fn dup_fd(fd: i32) -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    let handle = if fd == 1 {
+        io::stdout().as_handle().try_clone_to_owned()?
+    } else {
+        io::stderr().as_handle().try_clone_to_owned()?
+    };
+    Ok(File::from(handle))
+}
+
+/// Parse command line arguments, correctly handle quotes, spaces and escapes
+///
+/// Rules:
+/// - Inside single quotes: all characters are literals, including double quotes and backslashes
+/// - Inside double quotes: preserve spaces, single quotes treated as normal chars, \ only escapes ", $, ` and \ itself
+/// - Backslash outside quotes: escape next character, make it literal, backslash itself removed
+/// - Consecutive spaces outside quotes treated as separators
+/// - Adjacent quoted strings are concatenated (when no space separates them)
+/// - Quotes make a word even when empty: `""` is an empty argument
+/// - `$NAME` and `${NAME}` expand to the variable's value outside single quotes
+///   (undefined variables expand to nothing); `$?` expands to the last exit
+///   status, `$$` to the shell's PID, `$!` to the last background job's PID
+///   and `$RANDOM` to a new number from 0 to 32767
+/// - `$(...)` and backticks are replaced by the command's output; unquoted
+///   output is split into separate arguments on whitespace
+/// - Unquoted braces expand first: `file.{txt,md}` becomes `file.txt file.md`
+/// - An unquoted `~` or `~user` starting a word expands to that home directory
+/// - Words with unquoted `*`, `?` or `[...]` expand to the sorted matching file
+///   names, or stay as typed when nothing matches
+///
+/// Returns: token array containing command and all arguments, or an error if
+/// a quote is left open
+fn parse_args(input: &str, state: &mut ShellState) -> Result<Vec<String>, String> {
+    parse_words(input, state, true)
+}
+
+/// Expand a here-string's word like a double-quoted one: quotes are removed
+/// and variables, `~` and substitutions expanded, but it isn't split into
+/// words, brace-expanded or globbed
+fn expand_here_string(word: &str, state: &mut ShellState) -> Result<String, String> {
+    Ok(parse_words(word, state, false)?.concat())
+}
+
+/// The expansion behind `parse_args`; with `split` false, the input stays
+/// one word, without brace expansion or globbing
+fn parse_words(input: &str, state: &mut ShellState, split: bool) -> Result<Vec<String>, String> {
+    let input = if split {
+        expand_braces(input)
+    } else {
+        input.to_string()
+    };
+    let mut args = Vec::new();
+    let mut current_arg = Word::default();
     let mut chars = input.chars().peekable();
-    let mut command_part = String::new();
     let mut in_single_quote = false;
     let mut in_double_quote = false;
-    let mut escaped = false;
-    let mut stdout_file: Option<String> = None;
-    let mut stdout_append = false;
-    let mut stderr_file: Option<String> = None;
-    let mut stderr_append = false;
+    let mut escaped = false; // Track if in escape state (only outside quotes)
+
+    while let Some(ch) = chars.next() {
+        if escaped {
+            // If previous character was backslash (outside quotes), current char is literal
+            current_arg.push(ch);
+            escaped = false;
+            continue;
+        }
+
+        match ch {
+            '\\' if in_double_quote => {
+                // Backslash inside double quotes: only escape specific chars (", \, $, `)
+                if let Some(&next_ch) = chars.peek() {
+                    if matches!(next_ch, '"' | '\\' | '$' | '`') {
+                        // Escape: skip backslash, add escaped character
+                        chars.next(); // Consume next character
+                        current_arg.push(next_ch);
+                    } else {
+                        // Other characters: backslash as literal
+                        current_arg.push('\\');
+                    }
+                } else {
+                    // Backslash is last character, treat as literal
+                    current_arg.push('\\');
+                }
+            }
+            '\\' if !in_single_quote && !in_double_quote => {
+                // Backslash outside quotes: set escape flag, backslash itself not added
+                escaped = true;
+            }
+            '\'' if !in_double_quote => {
+                // When not inside double quotes, toggle single quote state
+                in_single_quote = !in_single_quote;
+                current_arg.quoted = true;
+            }
+            '"' if !in_single_quote => {
+                // When not inside single quotes, toggle double quote state
+                in_double_quote = !in_double_quote;
+                current_arg.quoted = true;
+            }
+            '$' if !in_single_quote && starts_arithmetic(&chars) => {
+                // Arithmetic expansion: $((...))
+                current_arg.push_str(&expand_arithmetic(&mut chars, state));
+            }
+            '$' if !in_single_quote && chars.peek() == Some(&'(') => {
+                // Command substitution: $(...)
+                chars.next(); // Consume '('
+                let command = read_substitution_body(&mut chars);
+                let output = capture_command_output(&command, state);
+                push_substitution(
+                    &output,
+                    in_double_quote || !split,
+                    &mut args,
+                    &mut current_arg,
+                );
+            }
+            '$' if !in_single_quote => {
+                // Variable expansion (also inside double quotes)
+                current_arg.push_str(&expand_variable(&mut chars, state));
+            }
+            '<' | '>' if !in_single_quote && !in_double_quote && chars.peek() == Some(&'(') => {
+                // Process substitution: <(...) or >(...)
+                chars.next(); // Consume '('
+                let command = read_substitution_body(&mut chars);
+                let path = start_process_substitution(&command, ch == '>', state);
+                current_arg.push_str(&path);
+            }
+            '~' if !in_single_quote
+                && !in_double_quote
+                && !current_arg.quoted
+                && current_arg.is_empty() =>
+            {
+                // Tilde expansion at the start of a word
+                current_arg.push_str(&expand_tilde(&mut chars, state));
+            }
+            '`' if !in_single_quote => {
+                // Command substitution: `...` (a backslash escapes a nested backtick)
+                let mut command = String::new();
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '`' => break,
+                        '\\' if chars.peek() == Some(&'`') => {
+                            command.push('`');
+                            chars.next();
+                        }
+                        _ => command.push(ch),
+                    }
+                }
+                let output = capture_command_output(&command, state);
+                push_substitution(
+                    &output,
+                    in_double_quote || !split,
+                    &mut args,
+                    &mut current_arg,
+                );
+            }
+            ' ' if split && !in_single_quote && !in_double_quote => {
+                // Space outside quotes: if current arg is not empty (or was quoted), complete current arg
+                if current_arg.quoted || !current_arg.is_empty() {
+                    std::mem::take(&mut current_arg).finish(&mut args);
+                }
+                // Skip consecutive spaces
+            }
+            '*' | '?' | '[' if split && !in_single_quote && !in_double_quote => {
+                // Unquoted glob metacharacter
+                current_arg.push_glob(ch);
+            }
+            _ => {
+                // Other characters added directly to current arg (including all chars inside quotes)
+                current_arg.push(ch);
+            }
+        }
+    }
+
+    // Like bash, an unclosed quote is an error rather than closed implicitly
+    if in_single_quote || in_double_quote {
+        let quote = if in_single_quote { '\'' } else { '"' };
+        return Err(format!(
+            "unexpected EOF while looking for matching `{}'",
+            quote
+        ));
+    }
+
+    // Handle last argument
+    if current_arg.quoted || !current_arg.is_empty() {
+        current_arg.finish(&mut args);
+    }
+
+    Ok(args)
+}
+
+/// An argument being built by `parse_args`
+///
+/// Alongside the final text it keeps a glob pattern in which quoted or
+/// escaped metacharacters are backslash-escaped, so only unquoted `*`, `?`
+/// and `[` act as wildcards.
+#[derive(Default)]
+struct Word {
+    text: String,
+    pattern: String,
+    has_glob: bool,
+    /// Whether part of the word was quoted, so it's kept even if empty
+    quoted: bool,
+}
+
+impl Word {
+    /// Append a literal character
+    fn push(&mut self, ch: char) {
+        self.text.push(ch);
+        if matches!(ch, '*' | '?' | '[' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(ch);
+    }
+
+    /// Append literal text
+    fn push_str(&mut self, text: &str) {
+        text.chars().for_each(|ch| self.push(ch));
+    }
+
+    /// Append an unquoted glob metacharacter
+    fn push_glob(&mut self, ch: char) {
+        self.text.push(ch);
+        self.pattern.push(ch);
+        self.has_glob = true;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Add the finished word to `args`, replaced by its sorted filename
+    /// matches if it is a glob pattern that matches anything
+    fn finish(self, args: &mut Vec<String>) {
+        if self.has_glob {
+            let matches = expand_glob(&self.pattern);
+            if !matches.is_empty() {
+                args.extend(matches);
+                return;
+            }
+        }
+        // No wildcard, or nothing matched: keep the word literally
+        args.push(self.text);
+    }
+}
+
+/// Brace-expand each word of `input`, before any other expansion
+///
+/// Like bash, a group needs an unquoted comma (`{a,b}`) or to be a sequence
+/// (`{1..5}`, `{a..e}`) to expand; groups can be nested, and several in one
+/// word give every combination. The words keep their quotes and escapes for
+/// `parse_args` to interpret.
+fn expand_braces(input: &str) -> String {
+    if !input.contains('{') {
+        return input.to_string();
+    }
+    mark_unquoted(input)
+        .split(|&(ch, unquoted)| ch == ' ' && unquoted)
+        .filter(|word| !word.is_empty())
+        .flat_map(expand_brace_word)
+        .map(|word| word.iter().map(|&(ch, _)| ch).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Characters of shell input, each with whether it's unquoted (see `mark_unquoted`)
+type MarkedChars = Vec<(char, bool)>;
+
+/// Pair each character of `input` with whether it's shell syntax, as opposed
+/// to quoted, escaped or inside a `$(...)`, `${...}` or backtick substitution
+fn mark_unquoted(input: &str) -> MarkedChars {
+    let mut marked = Vec::new();
+    let mut quote: Option<char> = None;
+    // Closing character of each substitution we're in, and the quote state outside it
+    let mut outer: Vec<(char, Option<char>)> = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        marked.push((ch, quote.is_none() && outer.is_empty()));
+        match (quote, ch) {
+            (Some('\'' | '`'), _) if Some(ch) == quote => quote = None,
+            (Some('\'' | '`'), _) => {}
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    marked.push((escaped, false));
+                }
+            }
+            (Some('"'), '"') => quote = None,
+            (None, '\'' | '"' | '`') => quote = Some(ch),
+            (None | Some('"'), '$') if matches!(chars.peek(), Some('(' | '{')) => {
+                let open = chars.next().unwrap_or_default();
+                marked.push((open, false));
+                let close = if open == '(' { ')' } else { '}' };
+                outer.push((close, quote.take()));
+            }
+            (None, '(') => outer.push((')', None)),
+            (None, _) if outer.last().is_some_and(|&(close, _)| close == ch) => {
+                quote = outer.pop().and_then(|(_, outer_quote)| outer_quote);
+            }
+            _ => {}
+        }
+    }
+    marked
+}
+
+/// Expand the brace groups of one word (from `mark_unquoted`)
+fn expand_brace_word(word: &[(char, bool)]) -> Vec<MarkedChars> {
+    for (start, &(ch, unquoted)) in word.iter().enumerate() {
+        if ch != '{' || !unquoted {
+            continue;
+        }
+        // Not a group (e.g. `{}` or an unmatched `{`): the brace is literal
+        let Some((end, items)) = brace_group(&word[start..]) else {
+            continue;
+        };
+        let preamble = &word[..start];
+        let postscripts = expand_brace_word(&word[start + end + 1..]);
+        let mut words = Vec::new();
+        for item in items {
+            for expanded in expand_brace_word(&item) {
+                for postscript in &postscripts {
+                    words.push([preamble, &expanded, postscript].concat());
+                }
+            }
+        }
+        return words;
+    }
+    vec![word.to_vec()]
+}
+
+/// Parse the brace group `group` starts with, returning the index of its
+/// closing brace and its items, or `None` if it isn't a valid group
+fn brace_group(group: &[(char, bool)]) -> Option<(usize, Vec<MarkedChars>)> {
+    let mut depth = 0;
+    let mut item_start = 1;
+    let mut items = Vec::new();
+    for (i, &(ch, unquoted)) in group.iter().enumerate().skip(1) {
+        if !unquoted {
+            continue;
+        }
+        match ch {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(group[item_start..i].to_vec());
+                item_start = i + 1;
+            }
+            '}' if items.is_empty() => {
+                // Without a comma, only a sequence like `{1..5}` is a group
+                let body = &group[1..i];
+                if body.iter().any(|&(_, unquoted)| !unquoted) {
+                    return None;
+                }
+                let body: String = body.iter().map(|&(ch, _)| ch).collect();
+                let items = brace_sequence(&body)?
+                    .into_iter()
+                    .map(|item| item.chars().map(|ch| (ch, true)).collect())
+                    .collect();
+                return Some((i, items));
+            }
+            '}' => {
+                items.push(group[item_start..i].to_vec());
+                return Some((i, items));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expand the body of a sequence group: `1..5`, `5..1`, `1..10..2` or `a..e`
+///
+/// The step's sign is ignored, as the ends give the direction. Numbers are
+/// zero-padded to the same width when an end has a leading zero, e.g.
+/// `01..10`. Returns `None` if the body isn't a sequence.
+fn brace_sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (
+            start,
+            end,
+            step.parse::<i64>()
+                .ok()?
+                .unsigned_abs()
+                .clamp(1, i64::MAX as u64),
+        ),
+        _ => return None,
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |number: &str| {
+            let digits = number.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(start) || padded(end) {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        return Some(
+            sequence(first, last, step)
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0width$}", -n, width = width.saturating_sub(1))
+                    } else {
+                        format!("{:0width$}", n, width = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    // A range of letters, e.g. `a..e`
+    let (mut start, mut end) = (start.chars(), end.chars());
+    match (start.next(), start.next(), end.next(), end.next()) {
+        (Some(first), None, Some(last), None)
+            if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() =>
+        {
+            Some(
+                sequence(first as i64, last as i64, step)
+                    .map(|c| char::from(c as u8).to_string())
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// The numbers from `first` to `last` (either way), `step` apart
+fn sequence(first: i64, last: i64, step: u64) -> impl Iterator<Item = i64> {
+    let count = first.abs_diff(last) / step + 1;
+    let step = if first <= last {
+        step as i64
+    } else {
+        -(step as i64)
+    };
+    (0..count as i64).map(move |i| first + i * step)
+}
+
+/// Expand a glob pattern against the filesystem, relative to the cwd
+///
+/// Returns the sorted matching paths, or nothing if there are none. Hidden
+/// entries only match when the pattern component itself starts with `.`.
+fn expand_glob(pattern: &str) -> Vec<String> {
+    let absolute = pattern.starts_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+
+    // Each candidate is (path to read from disk, path as displayed)
+    let mut candidates = vec![if absolute {
+        (PathBuf::from("/"), "/".to_string())
+    } else {
+        (PathBuf::from("."), String::new())
+    }];
+
+    for (i, component) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+        let mut next = Vec::new();
+
+        for (dir, shown) in &candidates {
+            let join = |name: &str| {
+                if shown.is_empty() || shown.ends_with('/') {
+                    format!("{}{}", shown, name)
+                } else {
+                    format!("{}/{}", shown, name)
+                }
+            };
+
+            if !component_has_glob(component) {
+                // Plain component: just descend, checking it exists
+                let name = unescape_glob(component);
+                let path = dir.join(&name);
+                if path.exists() && (is_last || path.is_dir()) {
+                    next.push((path, join(&name)));
+                }
+                continue;
+            }
+
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                let path = entry.path();
+                if glob_match(component, &name) && (is_last || path.is_dir()) {
+                    next.push((path, join(&name)));
+                }
+            }
+        }
+
+        candidates = next;
+    }
+
+    let mut matches: Vec<String> = candidates.into_iter().map(|(_, shown)| shown).collect();
+    matches.sort();
+    matches
+}
+
+/// Whether a pattern component contains an unescaped glob metacharacter
+fn component_has_glob(component: &str) -> bool {
+    let mut chars = component.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Remove the backslash escapes from a pattern component without wildcards
+fn unescape_glob(component: &str) -> String {
+    let mut result = String::new();
+    let mut chars = component.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+/// Match a file name against a glob pattern supporting `*`, `?`, `[...]`
+/// (with `!` or `^` negation and `a-z` ranges) and backslash escapes
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_from(&pattern, &name)
+}
+
+fn glob_match_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => {
+            // Try every possible length for the star, shortest first
+            (0..=name.len()).any(|skip| glob_match_from(&pattern[1..], &name[skip..]))
+        }
+        Some('?') => !name.is_empty() && glob_match_from(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(&ch) = name.first() else {
+                return false;
+            };
+            match match_bracket(&pattern[1..], ch) {
+                Some((matched, rest)) => matched && glob_match_from(rest, &name[1..]),
+                // Unclosed bracket: treat '[' literally
+                None => ch == '[' && glob_match_from(&pattern[1..], &name[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && glob_match_from(&pattern[2..], &name[1..])
+        }
+        Some(&literal) => {
+            name.first() == Some(&literal) && glob_match_from(&pattern[1..], &name[1..])
+        }
+    }
+}
+
+/// Match `ch` against a bracket expression whose opening `[` has been
+/// consumed, returning whether it matched and the pattern after the `]`
+fn match_bracket(pattern: &[char], ch: char) -> Option<(bool, &[char])> {
+    let (negated, mut i) = match pattern.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+
+    while i < pattern.len() {
+        let mut start = pattern[i];
+        // A ']' right after the opening bracket is literal
+        if start == ']' && !first {
+            return Some((matched != negated, &pattern[i + 1..]));
+        }
+        if start == '\\' && i + 1 < pattern.len() {
+            i += 1;
+            start = pattern[i];
+        }
+        first = false;
+
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let end = pattern[i + 2];
+            if start <= ch && ch <= end {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if start == ch {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+
+    None
+}
+
+/// Read the body of a `$(...)` substitution after its opening parenthesis,
+/// up to the matching close parenthesis (nested parentheses and quotes allowed)
+fn read_substitution_body(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut body = String::new();
+    let mut depth = 0;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
 
-    while let Some(ch) = chars.peek() {
-        // Handle quote state
-        if !escaped {
-            match ch {
-                '\\' if !in_single_quote => {
-                    escaped = true;
-                    command_part.push(chars.next().unwrap());
-                    continue;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                // Keep escapes for the inner command to interpret
+                body.push(ch);
+                if let Some(next) = chars.next() {
+                    body.push(next);
                 }
-                '\'' if !in_double_quote => {
-                    in_single_quote = !in_single_quote;
-                    command_part.push(chars.next().unwrap());
-                    continue;
+                continue;
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '(' if !in_single_quote && !in_double_quote => depth += 1,
+            ')' if !in_single_quote && !in_double_quote => {
+                if depth == 0 {
+                    break;
                 }
-                '"' if !in_single_quote => {
-                    in_double_quote = !in_double_quote;
-                    command_part.push(chars.next().unwrap());
-                    continue;
+                depth -= 1;
+            }
+            _ => {}
+        }
+        body.push(ch);
+    }
+
+    body
+}
+
+/// Whether the characters after a `$` start an arithmetic expansion `((`
+fn starts_arithmetic(chars: &std::iter::Peekable<std::str::Chars>) -> bool {
+    let mut ahead = chars.clone();
+    ahead.next() == Some('(') && ahead.next() == Some('(')
+}
+
+/// Evaluate the `$((...))` expansion following a `$`, returning the result
+///
+/// The body is expanded like a here-document first, so `$x` and `$(...)`
+/// work; bare names read variables too. Errors such as division by zero are
+/// reported and mark the expansion as failed, so the command doesn't run.
+fn expand_arithmetic(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    state: &mut ShellState,
+) -> String {
+    chars.next(); // Consume the two '('
+    chars.next();
+    let body = read_substitution_body(chars);
+    if chars.peek() == Some(&')') {
+        chars.next(); // Consume the second ')'
+    }
+
+    let expression = expand_heredoc_body(&body, state);
+    match evaluate_arithmetic(&expression, state) {
+        Ok(value) => value.to_string(),
+        Err(e) => {
+            eprintln!("{}: {}", expression.trim(), e);
+            state.expansion_error = true;
+            String::new()
+        }
+    }
+}
+
+/// A token of an arithmetic expression
+#[derive(Clone)]
+enum ArithToken {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+/// Operators, longest first so `**` wins over `*`
+const ARITH_OPERATORS: [&str; 25] = [
+    "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "&",
+    "^", "|", "!", "~", "(", ")", "?", ":",
+];
+
+/// Evaluate an integer expression with C-like operators and precedence
+fn evaluate_arithmetic(expression: &str, state: &ShellState) -> Result<i64, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(ch) = rest.chars().next() {
+        if ch.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(ArithToken::Number(parse_arith_number(&rest[..end])?));
+            rest = &rest[end..];
+        } else if ch.is_ascii_alphabetic() || ch == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(ArithToken::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let op = ARITH_OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| {
+                    format!(
+                        "syntax error: invalid arithmetic operator (error token is \"{}\")",
+                        rest
+                    )
+                })?;
+            tokens.push(ArithToken::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+
+    let mut parser = ArithParser {
+        tokens,
+        pos: 0,
+        state,
+    };
+    let value = parser.ternary()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(_) => Err("syntax error in expression".to_string()),
+    }
+}
+
+/// Parse a decimal, `0x` hexadecimal or `0`-prefixed octal integer
+fn parse_arith_number(text: &str) -> Result<i64, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if text.len() > 1 && text.starts_with('0') {
+        i64::from_str_radix(&text[1..], 8)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("value too great for base (error token is \"{}\")", text))
+}
+
+/// Precedence-climbing evaluator over arithmetic tokens
+struct ArithParser<'a> {
+    tokens: Vec<ArithToken>,
+    pos: usize,
+    state: &'a ShellState,
+}
+
+impl ArithParser<'_> {
+    /// Binary operators and their precedence, loosest first
+    const BINARY: [(&'static str, u8); 18] = [
+        ("||", 1),
+        ("&&", 2),
+        ("|", 3),
+        ("^", 4),
+        ("&", 5),
+        ("==", 6),
+        ("!=", 6),
+        ("<", 7),
+        ("<=", 7),
+        (">", 7),
+        (">=", 7),
+        ("<<", 8),
+        (">>", 8),
+        ("+", 9),
+        ("-", 9),
+        ("*", 10),
+        ("/", 10),
+        ("%", 10),
+    ];
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(ArithToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("syntax error: `{}' expected", op))
+        }
+    }
+
+    /// `cond ? a : b`, the loosest operator
+    fn ternary(&mut self) -> Result<i64, String> {
+        let condition = self.binary(1)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.ternary()?;
+        self.expect(":")?;
+        let otherwise = self.ternary()?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    /// Binary operators binding at least as tightly as `min`
+    fn binary(&mut self, min: u8) -> Result<i64, String> {
+        let mut left = self.power()?;
+        while let Some((op, precedence)) = self
+            .peek_op()
+            .and_then(|op| Self::BINARY.iter().find(|(o, _)| *o == op))
+            .filter(|(_, precedence)| *precedence >= min)
+        {
+            self.pos += 1;
+            let right = self.binary(precedence + 1)?;
+            left = match *op {
+                "||" => (left != 0 || right != 0) as i64,
+                "&&" => (left != 0 && right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                "<=" => (left <= right) as i64,
+                ">" => (left > right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ if right == 0 => return Err("division by zero".to_string()),
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right),
+            };
+        }
+        Ok(left)
+    }
+
+    /// `a ** b`, right-associative and tighter than `*`
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if self.peek_op() != Some("**") {
+            return Ok(base);
+        }
+        self.pos += 1;
+        let exponent = self.power()?;
+        let exponent = u32::try_from(exponent).map_err(|_| "exponent less than 0".to_string())?;
+        Ok(base.wrapping_pow(exponent))
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(ArithToken::Number(n)) => Ok(n),
+            Some(ArithToken::Name(name)) => {
+                // Unset or empty variables count as 0
+                let value = self.state.var(&name).unwrap_or("").trim();
+                if value.is_empty() {
+                    Ok(0)
+                } else {
+                    parse_arith_number(value.strip_prefix('-').unwrap_or(value))
+                        .map(|n| if value.starts_with('-') { -n } else { n })
                 }
-                '>' if !in_single_quote && !in_double_quote => {
-                    // Found redirection operator
-                    chars.next(); // Consume '>'
+            }
+            Some(ArithToken::Op("(")) => {
+                let value = self.ternary()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(ArithToken::Op("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(ArithToken::Op("+")) => self.unary(),
+            Some(ArithToken::Op("!")) => Ok((self.unary()? == 0) as i64),
+            Some(ArithToken::Op("~")) => Ok(!self.unary()?),
+            Some(ArithToken::Op(op)) => Err(format!(
+                "syntax error: operand expected (error token is \"{}\")",
+                op
+            )),
+            None => Err("syntax error: operand expected".to_string()),
+        }
+    }
+}
+
+/// Add command substitution output to the argument being built
+///
+/// Inside double quotes the output stays part of the current argument;
+/// unquoted, it is split on whitespace into separate arguments.
+fn push_substitution(output: &str, quoted: bool, args: &mut Vec<String>, current_arg: &mut Word) {
+    if quoted {
+        current_arg.push_str(output);
+        return;
+    }
+
+    for (i, word) in output.split_whitespace().enumerate() {
+        if i > 0 {
+            std::mem::take(current_arg).finish(args);
+        }
+        current_arg.push_str(word);
+    }
+}
+
+/// Run a command in a forked subshell and return what it wrote to stdout,
+/// with trailing newlines removed
+///
+/// The subshell gets a copy of the shell state, so it sees variables and
+/// options but can't change them. `$?` is set to the command's status.
+#[cfg(unix)]
+fn capture_command_output(command: &str, state: &mut ShellState) -> String {
+    let mut pipe_fds = [0i32; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } != 0 {
+        eprintln!("command substitution: {}", io::Error::last_os_error());
+        return String::new();
+    }
+    let (read_fd, write_fd) = (pipe_fds[0], pipe_fds[1]);
+
+    // Flush first so pending output isn't written twice by the child
+    let _ = io::stdout().flush();
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        eprintln!("command substitution: {}", io::Error::last_os_error());
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return String::new();
+    }
+
+    if pid == 0 {
+        // Child process: send stdout into the pipe and run the command
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+            libc::close(read_fd);
+            libc::dup2(write_fd, 1);
+            libc::close(write_fd);
+        }
+        enter_subshell(state);
+        if let Err(e) = execute_line(command, state) {
+            eprintln!("Execution error: {}", e);
+        }
+        let _ = io::stdout().flush();
+        std::process::exit(state.last_status);
+    }
+
+    // Parent process: read everything the child writes, then reap it
+    unsafe { libc::close(write_fd) };
+    let mut output = Vec::new();
+    let mut reader = unsafe { File::from_raw_fd(read_fd) };
+    let _ = reader.read_to_end(&mut output);
+    drop(reader);
+
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+    state.last_status = wait_status_code(status);
+
+    String::from_utf8_lossy(&output)
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+/// Run a command in a new copy of the shell and return what it wrote to
+/// stdout, with trailing newlines removed
+///
+/// Without `fork`, the subshell is started afresh with the command on its
+/// stdin: it sees exported variables, but not aliases, options or unexported
+/// variables. `$?` is set to the command's status.
+#[cfg(not(unix))]
+fn capture_command_output(command: &str, state: &mut ShellState) -> String {
+    let child = env::current_exe().and_then(|shell| {
+        Command::new(shell)
+            .env_clear()
+            .envs(&state.env)
+            // The subshell's commands aren't part of the history
+            .env_remove("HISTFILE")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+    });
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("command substitution: {}", e);
+            return String::new();
+        }
+    };
+
+    // Feed the command from a thread so a full stdout pipe can't block it
+    if let Some(mut stdin) = child.stdin.take() {
+        let script = format!("{}\n", command);
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(script.as_bytes());
+        });
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("command substitution: {}", e);
+            return String::new();
+        }
+    };
+    state.last_status = exit_status_code(output.status);
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+/// Set up the state of a forked subshell before it runs its commands
+#[cfg(unix)]
+fn enter_subshell(state: &mut ShellState) {
+    state.subshell = true;
+    // Like bash, traps set in the shell don't run in its subshells; only
+    // ignored signals stay ignored
+    state.traps.retain(|&signal, command| {
+        if !command.is_empty() && signal != 0 {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+        command.is_empty()
+    });
+    // The shell's scanning thread didn't survive the fork
+    state.executables = ExecutableCache::unscanned(state.var("PATH"));
+}
+
+/// Start a process substitution in a forked subshell connected to a pipe,
+/// returning the `/dev/fd/N` path of the shell's end
+///
+/// For `<(command)` the path reads the command's output; for `>(command)`
+/// (`to_command`) writing to it feeds the command's input. The shell's end
+/// stays open, to be inherited by the command being expanded, until
+/// `finish_process_substitutions` runs after it.
+#[cfg(unix)]
+fn start_process_substitution(command: &str, to_command: bool, state: &mut ShellState) -> String {
+    let mut pipe_fds = [0i32; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } != 0 {
+        eprintln!("process substitution: {}", io::Error::last_os_error());
+        return String::new();
+    }
+    let (read_fd, write_fd) = (pipe_fds[0], pipe_fds[1]);
+    // The subshell uses one end as its stdin or stdout; the shell keeps the other
+    let (child_fd, shell_fd, target_fd) = if to_command {
+        (read_fd, write_fd, 0)
+    } else {
+        (write_fd, read_fd, 1)
+    };
+
+    // Flush first so pending output isn't written twice by the child
+    let _ = io::stdout().flush();
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        eprintln!("process substitution: {}", io::Error::last_os_error());
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return String::new();
+    }
+
+    if pid == 0 {
+        // Child process: connect the pipe and run the command
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+            libc::close(shell_fd);
+            libc::dup2(child_fd, target_fd);
+            libc::close(child_fd);
+            // Earlier substitutions' ends belong to the shell, not this subshell
+            for (fd, _) in &state.process_substitutions {
+                libc::close(*fd);
+            }
+        }
+        state.process_substitutions.clear();
+        enter_subshell(state);
+        if to_command {
+            // Stdin is now the substitution's input, for its commands to read
+            state.stdin_is_script = false;
+        }
+        if let Err(e) = execute_line(command, state) {
+            eprintln!("Execution error: {}", e);
+        }
+        let _ = io::stdout().flush();
+        std::process::exit(state.last_status);
+    }
+
+    unsafe { libc::close(child_fd) };
+    state.process_substitutions.push((shell_fd, pid));
+    format!("/dev/fd/{}", shell_fd)
+}
+
+/// Report that process substitution isn't available, so the command being
+/// expanded doesn't run
+///
+/// It needs `fork` and `/dev/fd` paths, which this platform doesn't have.
+#[cfg(not(unix))]
+fn start_process_substitution(_command: &str, _to_command: bool, state: &mut ShellState) -> String {
+    eprintln!("process substitution: not supported on this platform");
+    state.expansion_error = true;
+    String::new()
+}
+
+/// Close the shell's ends of the process substitutions started for the last
+/// command and wait for their subshells
+#[cfg(unix)]
+fn finish_process_substitutions(state: &mut ShellState) {
+    for (fd, pid) in state.process_substitutions.drain(..) {
+        let mut status = 0;
+        unsafe {
+            libc::close(fd);
+            libc::waitpid(pid, &mut status, 0);
+        }
+    }
+}
+
+/// No process substitutions are started here, so there are none to finish
+#[cfg(not(unix))]
+fn finish_process_substitutions(_state: &mut ShellState) {}
+
+/// Expand the tilde prefix following a `~` that has just been consumed
+///
+/// `~` alone becomes `$HOME`, `~user` becomes that user's home directory from
+/// the passwd database. The prefix must end at `/` or the end of the word;
+/// otherwise, or for an unknown user, it is returned literally.
+fn expand_tilde(chars: &mut std::iter::Peekable<std::str::Chars>, state: &ShellState) -> String {
+    // Look ahead for the user name without consuming anything yet
+    let user: String = chars
+        .clone()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .collect();
+    let after = chars.clone().nth(user.chars().count());
+    if !matches!(after, None | Some('/') | Some(' ')) {
+        return "~".to_string();
+    }
+
+    let home = if user.is_empty() {
+        state.var("HOME").map(|h| h.to_string())
+    } else {
+        user_home_dir(&user)
+    };
+
+    match home {
+        Some(home) => {
+            // Consume the user name now that it has been expanded
+            for _ in 0..user.chars().count() {
+                chars.next();
+            }
+            home
+        }
+        None => "~".to_string(),
+    }
+}
+
+/// Look up a user's home directory in the passwd database
+#[cfg(unix)]
+fn user_home_dir(user: &str) -> Option<String> {
+    let name = std::ffi::CString::new(user).ok()?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr((*entry).pw_dir) };
+    Some(dir.to_string_lossy().into_owned())
+}
+
+/// Other users' home directories can't be looked up without a passwd
+/// database, so `~user` is left as written
+#[cfg(not(unix))]
+fn user_home_dir(_user: &str) -> Option<String> {
+    None
+}
+
+/// Expand the variable reference following a `$` that has just been consumed
+///
+/// Handles `$NAME`, `${NAME}`, `$?`, `$$` and `$!`. Anything else (including
+/// an unclosed `${`) is returned literally.
+fn expand_variable(chars: &mut std::iter::Peekable<std::str::Chars>, state: &ShellState) -> String {
+    match chars.peek() {
+        Some('{') => {
+            chars.next(); // Consume '{'
+            let mut name = String::new();
+            for ch in chars.by_ref() {
+                if ch == '}' {
+                    return variable_value(&name, state);
+                }
+                name.push(ch);
+            }
+            // No closing brace: keep the text as typed
+            format!("${{{}", name)
+        }
+        Some(&ch @ ('$' | '!' | '?')) => {
+            chars.next(); // Consume the special parameter
+            variable_value(&ch.to_string(), state)
+        }
+        Some(&ch) if ch.is_ascii_alphabetic() || ch == '_' => {
+            let mut name = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_ascii_alphanumeric() || ch == '_' {
+                    name.push(ch);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            variable_value(&name, state)
+        }
+        _ => "$".to_string(),
+    }
+}
+
+/// Value of a variable or special parameter; these and `RANDOM` (different
+/// each time) are read-only
+fn variable_value(name: &str, state: &ShellState) -> String {
+    match name {
+        "?" => state.last_status.to_string(),
+        "$" => state.shell_pid.to_string(),
+        "!" => state
+            .last_background_pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_default(),
+        "RANDOM" => random_number().to_string(),
+        _ => state.var(name).unwrap_or_default().to_string(),
+    }
+}
+
+/// Next number in 0..=32767 for `$RANDOM`, from a xorshift generator seeded
+/// with the clock and PID on first use
+fn random_number() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+
+    let mut x = STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        x = (nanos ^ u64::from(std::process::id()) << 32) | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    (x >> 33) % 32768
+}
+
+/// Dynamic search logic (for type command), over the given PATH value
+fn find_command_in_path(command: &str, path: Option<&str>) -> Option<PathBuf> {
+    path.and_then(|paths| {
+        env::split_paths(paths)
+            .flat_map(|dir| command_candidates(&dir, command))
+            .find(|full_path| is_executable(full_path))
+    })
+}
+
+/// Find every executable named `command` in PATH, in PATH order
+fn find_all_in_path(command: &str, path: Option<&str>) -> Vec<PathBuf> {
+    path.map(|paths| {
+        env::split_paths(paths)
+            .flat_map(|dir| command_candidates(&dir, command))
+            .filter(|full_path| is_executable(full_path))
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Name a command is run by, given the path of its executable
+#[cfg(not(windows))]
+fn command_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|n| n.to_str())
+}
+
+/// Name a command is run by, given the path of its executable
+///
+/// On Windows `git` runs `git.exe`, so the extension is left out.
+#[cfg(windows)]
+fn command_name(path: &Path) -> Option<&str> {
+    path.file_stem().and_then(|n| n.to_str())
+}
 
-                    // Check if it's append mode '>>'
-                    let is_append = if chars.peek() == Some(&'>') {
-                        chars.next(); // Consume second '>'
-                        true
-                    } else {
-                        false
-                    };
+/// Paths in `dir` that could hold the executable for `command`
+#[cfg(not(windows))]
+fn command_candidates(dir: &Path, command: &str) -> Vec<PathBuf> {
+    vec![dir.join(command)]
+}
 
-                    // Skip spaces
-                    while chars.peek() == Some(&' ') {
-                        chars.next();
-                    }
+/// Paths in `dir` that could hold the executable for `command`: the name as
+/// given, then with each `PATHEXT` extension
+#[cfg(windows)]
+fn command_candidates(dir: &Path, command: &str) -> Vec<PathBuf> {
+    std::iter::once(dir.join(command))
+        .chain(
+            executable_extensions()
+                .iter()
+                .map(|ext| dir.join(format!("{}{}", command, ext))),
+        )
+        .collect()
+}
 
-                    // Get output filename (read until next redirect or end)
-                    let file = parse_filename(&mut chars);
+/// Extensions Windows runs directly, from `PATHEXT` (e.g. `.EXE`)
+#[cfg(windows)]
+fn executable_extensions() -> Vec<String> {
+    env::var("PATHEXT")
+        .unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string())
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| ext.to_string())
+        .collect()
+}
 
-                    if !file.is_empty() {
-                        stdout_file = Some(file);
-                        stdout_append = is_append;
-                    }
-                    continue;
-                }
-                '1' if !in_single_quote && !in_double_quote => {
-                    // Check if it's "1>" or "1>>" form
-                    let mut temp_chars = chars.clone();
-                    temp_chars.next(); // Skip '1'
-                    if temp_chars.peek() == Some(&'>') {
-                        chars.next(); // Consume '1'
-                        chars.next(); // Consume '>'
+/// Executables found in PATH, by name, shared by command lookup and completion
+///
+/// Scans run on a background thread, started at startup and again whenever
+/// PATH changes; until one is done, lookups search PATH directly and
+/// completion waits for it.
+#[derive(Clone)]
+struct ExecutableCache {
+    shared: Arc<(Mutex<ExecutableScan>, Condvar)>,
+}
 
-                        // Check if it's append mode '1>>'
-                        let is_append = if chars.peek() == Some(&'>') {
-                            chars.next(); // Consume second '>'
-                            true
-                        } else {
-                            false
-                        };
+/// A PATH and the executables found in it (`None` until scanned)
+struct ExecutableScan {
+    path: Option<String>,
+    executables: Option<HashMap<String, PathBuf>>,
+    /// Whether a background thread is scanning `path`
+    scanning: bool,
+}
 
-                        // Skip spaces
-                        while chars.peek() == Some(&' ') {
-                            chars.next();
-                        }
+impl ExecutableCache {
+    /// Start scanning `path` on a background thread
+    fn scan_in_background(path: Option<String>) -> Self {
+        let cache = ExecutableCache::unscanned(None);
+        cache.start_scan(path);
+        cache
+    }
 
-                        // Get output filename
-                        let file = parse_filename(&mut chars);
+    /// A cache for `path` that isn't scanned until a full list is needed
+    ///
+    /// Forked subshells use this: they don't get the shell's scanning thread,
+    /// so a copy of its unfinished scan would never complete.
+    fn unscanned(path: Option<&str>) -> Self {
+        ExecutableCache {
+            shared: Arc::new((
+                Mutex::new(ExecutableScan {
+                    path: path.map(str::to_string),
+                    executables: None,
+                    scanning: false,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
 
-                        if !file.is_empty() {
-                            stdout_file = Some(file);
-                            stdout_append = is_append;
-                        }
-                    } else {
-                        command_part.push(chars.next().unwrap());
-                    }
-                    continue;
-                }
-                '2' if !in_single_quote && !in_double_quote => {
-                    // Check if it's "2>" or "2>>" form (stderr redirection)
-                    let mut temp_chars = chars.clone();
-                    temp_chars.next(); // Skip '2'
-                    if temp_chars.peek() == Some(&'>') {
-                        chars.next(); // Consume '2'
-                        chars.next(); // Consume '>'
+    /// Replace the result with a background scan of `path`
+    fn start_scan(&self, path: Option<String>) {
+        let mut scan = self.shared.0.lock().unwrap_or_else(PoisonError::into_inner);
+        scan.path = path.clone();
+        scan.executables = None;
+        scan.scanning = true;
+        drop(scan);
 
-                        // Check if it's append mode '2>>'
-                        let is_append = if chars.peek() == Some(&'>') {
-                            chars.next(); // Consume second '>'
-                            true
-                        } else {
-                            false
-                        };
+        let shared = Arc::clone(&self.shared);
+        std::thread::spawn(move || {
+            let executables = get_all_executables(path.as_deref());
+            let (scan, done) = &*shared;
+            let mut scan = scan.lock().unwrap_or_else(PoisonError::into_inner);
+            // PATH changed again while scanning: a newer scan will finish instead
+            if scan.path == path {
+                scan.executables = Some(executables);
+                scan.scanning = false;
+                done.notify_all();
+            }
+        });
+    }
 
-                        // Skip spaces
-                        while chars.peek() == Some(&' ') {
-                            chars.next();
-                        }
+    /// Scan again if `path` isn't the PATH the cache was built from
+    fn follow_path(&self, path: Option<&str>) {
+        let scan = self.shared.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if scan.path.as_deref() != path {
+            drop(scan);
+            self.start_scan(path.map(str::to_string));
+        }
+    }
 
-                        // Get output filename
-                        let file = parse_filename(&mut chars);
+    /// The finished scan, waiting for it if it's still running (or scanning
+    /// here if nothing is)
+    fn wait(&self) -> MutexGuard<'_, ExecutableScan> {
+        let (scan, done) = &*self.shared;
+        let mut scan = scan.lock().unwrap_or_else(PoisonError::into_inner);
+        if scan.executables.is_none() && !scan.scanning {
+            scan.executables = Some(get_all_executables(scan.path.as_deref()));
+        }
+        done.wait_while(scan, |scan| scan.executables.is_none())
+            .unwrap_or_else(PoisonError::into_inner)
+    }
 
-                        if !file.is_empty() {
-                            stderr_file = Some(file);
-                            stderr_append = is_append;
-                        }
-                    } else {
-                        command_part.push(chars.next().unwrap());
+    /// Whether `command` is an executable in PATH
+    fn contains(&self, command: &str) -> bool {
+        self.get(command).is_some()
+    }
+
+    /// Path of the executable `command` runs
+    fn get(&self, command: &str) -> Option<PathBuf> {
+        let scan = self.shared.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match &scan.executables {
+            Some(executables) => executables.get(command).cloned(),
+            None => find_command_in_path(command, scan.path.as_deref()),
+        }
+    }
+
+    /// Names of the executables starting with `prefix`
+    fn names_starting_with(&self, prefix: &str) -> Vec<String> {
+        let scan = self.wait();
+        let executables = scan.executables.as_ref().expect("scan is finished");
+        executables
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Names of all the executables, or `None` if the scan isn't done yet
+    fn scanned_names(&self) -> Option<Vec<String>> {
+        let scan = self.shared.0.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(scan.executables.as_ref()?.keys().cloned().collect())
+    }
+
+    /// Scan `path` again, replacing the current result
+    fn rebuild(&self, path: Option<&str>) {
+        // Wait for a running scan, so it can't overwrite the new one
+        let mut scan = self.wait();
+        scan.executables = Some(get_all_executables(path));
+        scan.path = path.map(str::to_string);
+    }
+}
+
+/// Preload all external commands (for execution validation)
+fn get_all_executables(path: Option<&str>) -> HashMap<String, PathBuf> {
+    let mut map = HashMap::new();
+
+    if let Some(paths) = path {
+        for dir in env::split_paths(&paths) {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if is_executable(&path)
+                        && let Some(name) = command_name(&path)
+                    {
+                        map.entry(name.to_string()).or_insert(path);
                     }
-                    continue;
                 }
-                _ => {}
             }
         }
+    }
+    map
+}
 
-        escaped = false;
-        command_part.push(chars.next().unwrap());
+/// Make SIGINT a no-op for the shell itself
+///
+/// A caught signal (unlike an ignored one) is reset to its default on exec,
+/// so commands started by the shell can still be interrupted with Ctrl-C.
+/// At the prompt, the line editor reads Ctrl-C as a key instead.
+fn install_sigint_handler() {
+    extern "C" fn ignore_sigint(_: libc::c_int) {}
+    let handler: extern "C" fn(libc::c_int) = ignore_sigint;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
     }
+}
 
-    // Build redirection info
-    let redirection = if stdout_file.is_some() || stderr_file.is_some() {
-        Some(Redirection {
-            stdout_file,
-            stdout_append,
-            stderr_file,
-            stderr_append,
+/// Signals `trap` accepts, by name; `EXIT` (0) is the shell exiting
+#[cfg(unix)]
+const TRAP_SIGNALS: &[(&str, i32)] = &[
+    ("EXIT", 0),
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("TERM", libc::SIGTERM),
+];
+
+/// Signals `trap` accepts, by name; outside Unix only `INT` and `TERM` can be caught
+#[cfg(not(unix))]
+const TRAP_SIGNALS: &[(&str, i32)] = &[("EXIT", 0), ("INT", libc::SIGINT), ("TERM", libc::SIGTERM)];
+
+/// Trapped signals caught but not yet handled, one bit per signal number
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+/// Look up a `trap` signal by name (`INT`, `SIGINT`, any case) or number
+fn trap_signal(spec: &str) -> Option<i32> {
+    let upper = spec.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    TRAP_SIGNALS
+        .iter()
+        .find(|(signal_name, signal)| *signal_name == name || signal.to_string() == spec)
+        .map(|&(_, signal)| signal)
+}
+
+/// Handle the `trap` builtin, returning the exit status
+///
+/// `trap 'cmd' SIG...` runs `cmd` when a signal arrives (once the running
+/// command finishes, like bash), `trap '' SIG...` ignores it and `trap - SIG...`
+/// (or `trap SIG`) restores the default. Without arguments, lists the traps.
+fn handle_trap(args: &[String], state: &mut ShellState) -> i32 {
+    // A lone signal, or `-` before signals, resets them; otherwise the
+    // first argument is the command
+    let (command, specs) = match args {
+        [] => {
+            print_traps(state);
+            return 0;
+        }
+        [_] => (None, args),
+        [first, specs @ ..] if first == "-" => (None, specs),
+        [first, specs @ ..] => (Some(first.as_str()), specs),
+    };
+
+    let mut status = 0;
+    for spec in specs {
+        let Some(signal) = trap_signal(spec) else {
+            eprintln!("trap: {}: invalid signal specification", spec);
+            status = 1;
+            continue;
+        };
+        match command {
+            Some(command) => state.traps.insert(signal, command.to_string()),
+            None => state.traps.remove(&signal),
+        };
+        install_trap_handler(signal, command);
+    }
+    status
+}
+
+/// List the traps as `trap` commands that would set them again
+fn print_traps(state: &ShellState) {
+    let mut traps: Vec<(&i32, &String)> = state.traps.iter().collect();
+    traps.sort();
+    for (signal, command) in traps {
+        let name = TRAP_SIGNALS
+            .iter()
+            .find(|&&(_, number)| number == *signal)
+            .map_or("", |&(name, _)| name);
+        let prefix = if *signal == 0 { "" } else { "SIG" };
+        println!(
+            "trap -- '{}' {}{}",
+            command.replace('\'', "'\\''"),
+            prefix,
+            name
+        );
+    }
+}
+
+/// Point a signal at the trap handler, ignore it (for an empty trap command),
+/// or restore the shell's own handling (for no command)
+fn install_trap_handler(signal: i32, command: Option<&str>) {
+    extern "C" fn record_signal(signal: libc::c_int) {
+        PENDING_SIGNALS.fetch_or(1 << signal, Ordering::SeqCst);
+    }
+
+    let handler = match command {
+        // EXIT isn't a signal; it's run by `run_exit_trap`
+        _ if signal == 0 => return,
+        Some("") => libc::SIG_IGN,
+        Some(_) => {
+            let handler: extern "C" fn(libc::c_int) = record_signal;
+            handler as libc::sighandler_t
+        }
+        None if signal == libc::SIGINT => return install_sigint_handler(),
+        None => libc::SIG_DFL,
+    };
+    unsafe {
+        libc::signal(signal, handler);
+    }
+}
+
+/// Run a trap's command, keeping `$?` as it was
+fn run_trap(command: &str, state: &mut ShellState) {
+    let status = state.last_status;
+    if let Err(e) = execute_line(command, state) {
+        eprintln!("Execution error: {}", e);
+    }
+    state.last_status = status;
+}
+
+/// Run the traps of signals caught since the last call
+fn run_pending_traps(state: &mut ShellState) {
+    let pending = PENDING_SIGNALS.swap(0, Ordering::SeqCst);
+    for &(_, signal) in &TRAP_SIGNALS[1..] {
+        if pending & (1 << signal) != 0
+            && let Some(command) = state.traps.get(&signal).cloned()
+        {
+            run_trap(&command, state);
+        }
+    }
+}
+
+/// Run the `EXIT` trap, if any, as the shell exits
+fn run_exit_trap(state: &mut ShellState) {
+    // Removed first, so an `exit` inside the trap doesn't run it again
+    if let Some(command) = state.traps.remove(&0) {
+        run_trap(&command, state);
+    }
+}
+
+/// Convert a child's exit status to a shell status code (128+N when killed by signal N)
+fn exit_status_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = status.signal() {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Whether a child was stopped by Ctrl-C
+#[cfg(unix)]
+fn interrupted(status: std::process::ExitStatus) -> bool {
+    status.signal() == Some(libc::SIGINT)
+}
+
+/// Whether a child was stopped by Ctrl-C, which it reports as the exit code
+/// `STATUS_CONTROL_C_EXIT`
+#[cfg(not(unix))]
+fn interrupted(status: std::process::ExitStatus) -> bool {
+    status.code() == Some(0xC000013A_u32 as i32)
+}
+
+/// Rebuild the executable cache from the current PATH, for both command
+/// lookup and completion
+fn rehash(state: &mut ShellState) {
+    state.executables.rebuild(state.var("PATH"));
+}
+
+/// Common check: whether path is a file the current user may execute
+///
+/// A symlink (as installed by Homebrew or other symlink farms) is judged by
+/// the file it points to, so a dangling one isn't executable.
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    let is_file = fs::metadata(path).is_ok_and(|m| m.is_file());
+    is_file && path.to_str().is_some_and(|path| file_access(path, 'x'))
+}
+
+/// Common check: whether path is a file with an extension listed in `PATHEXT`
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    let is_file = fs::metadata(path).map(|m| m.is_file()).unwrap_or(false);
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    is_file
+        && extension.is_some_and(|extension| {
+            executable_extensions()
+                .iter()
+                .any(|ext| ext.trim_start_matches('.').eq_ignore_ascii_case(extension))
         })
+}
+
+/// Format the selected history entries as `history` prints them
+fn history_output(history: &[String], selection: HistorySelection) -> String {
+    // Decide which history entries to show based on the selection
+    let (start_index, end_index) = selection.bounds(history.len());
+    let items_to_show = &history[start_index..end_index];
+
+    // Right-align numbers to the widest one shown (at least 5 columns, like bash)
+    let number_width = history.len().to_string().len().max(5);
+    // Only wrap to the terminal width when writing to a terminal
+    let columns = if io::stdout().is_terminal() {
+        terminal_width()
     } else {
         None
     };
 
-    (command_part.trim_end().to_string(), redirection)
+    // Display history, format: "    <line_number>  <command>"
+    let mut output = String::new();
+    for (i, cmd) in items_to_show.iter().enumerate() {
+        output.push_str(&format_history_entry(
+            start_index + i + 1,
+            cmd,
+            number_width,
+            columns,
+        ));
+        output.push('\n');
+    }
+    output
 }
 
-/// Parse filename from character iterator (until space, redirect or end)
-fn parse_filename(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
-    let mut filename = String::new();
+/// Format one history line: the entry number right-aligned in `number_width`
+/// columns followed by the command
+///
+/// When `columns` is given, long commands wrap with a hanging indent so the
+/// continuation stays aligned under the command column.
+fn format_history_entry(
+    number: usize,
+    cmd: &str,
+    number_width: usize,
+    columns: Option<usize>,
+) -> String {
+    let indent = number_width + 2;
+    let mut line = format!("{:>width$}  ", number, width = number_width);
 
-    while let Some(&ch) = chars.peek() {
-        // Stop condition: space, redirection operator or special character
-        if ch == ' ' || ch == '>' || ch == '1' || ch == '2' {
-            // Check if it's the start of a redirection operator
-            if ch == '1' || ch == '2' {
-                let mut temp = chars.clone();
-                temp.next();
-                if temp.peek() == Some(&'>') {
-                    // This is the next redirection operator, stop parsing
-                    break;
-                }
-            } else if ch == '>' || ch == ' ' {
-                break;
+    let chars: Vec<char> = cmd.chars().collect();
+    let chunk_len = match columns {
+        // Don't bother wrapping into absurdly narrow slivers
+        Some(cols) if cols > indent + 10 => cols - indent,
+        _ => chars.len().max(1),
+    };
+    for (i, chunk) in chars.chunks(chunk_len).enumerate() {
+        if i > 0 {
+            line.push('\n');
+            line.push_str(&" ".repeat(indent));
+        }
+        line.extend(chunk);
+    }
+
+    line
+}
+
+/// Width of the terminal in columns: `$COLUMNS` if set, otherwise the size
+/// the TIOCGWINSZ ioctl reports for stdout
+fn terminal_width() -> Option<usize> {
+    env_dimension("COLUMNS").or_else(|| {
+        terminal_winsize()
+            .map(|(columns, _)| columns)
+            .filter(|&columns| columns > 0)
+    })
+}
+
+/// Height of the terminal in rows: `$LINES` if set, otherwise the size
+/// the TIOCGWINSZ ioctl reports for stdout
+fn terminal_height() -> Option<usize> {
+    env_dimension("LINES").or_else(|| {
+        terminal_winsize()
+            .map(|(_, rows)| rows)
+            .filter(|&rows| rows > 0)
+    })
+}
+
+/// Read a positive terminal dimension from an environment variable
+fn env_dimension(var: &str) -> Option<usize> {
+    env::var(var)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Query the terminal size of stdout, as `(columns, rows)`
+#[cfg(unix)]
+fn terminal_winsize() -> Option<(usize, usize)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    ok.then_some((size.ws_col as usize, size.ws_row as usize))
+}
+
+/// The terminal size is only known from `$COLUMNS` and `$LINES` here
+#[cfg(not(unix))]
+fn terminal_winsize() -> Option<(usize, usize)> {
+    None
+}
+
+/// Write builtin output, through `$PAGER` (default `less`) when the `pager`
+/// option is on, stdout is a terminal and the output is taller than it
+fn write_paged(output: &str, state: &ShellState) {
+    let needs_pager = state.options.pager
+        && io::stdout().is_terminal()
+        && terminal_height().is_some_and(|rows| output.lines().count() >= rows);
+
+    if needs_pager {
+        let pager = state
+            .var("PAGER")
+            .filter(|p| !p.trim().is_empty())
+            .unwrap_or("less");
+        let mut words = pager.split_whitespace();
+        let program = words.next().unwrap_or("less");
+
+        if let Ok(mut child) = Command::new(program)
+            .args(words)
+            .env_clear()
+            .envs(&state.env)
+            .stdin(Stdio::piped())
+            .spawn()
+        {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The pager may quit before reading everything; that's fine
+                let _ = stdin.write_all(output.as_bytes());
             }
+            let _ = child.wait();
+            return;
         }
+    }
 
-        filename.push(chars.next().unwrap());
+    print!("{}", output);
+    let _ = io::stdout().flush();
+}
+
+/// Save history to HISTFILE (if the variable is set)
+///
+/// The commands run since the last write are merged into the file, so shells
+/// exiting one after another keep each other's history. Only the last
+/// `HISTFILESIZE` entries (default: `HISTSIZE`) are kept.
+fn save_history_to_histfile(state: &ShellState) {
+    let limit = history_limit(state.var("HISTFILESIZE").or(state.var("HISTSIZE")));
+
+    if let Some(histfile_path) = state.var("HISTFILE") {
+        let _ = merge_history_file(histfile_path, Some(limit), state);
     }
+}
 
-    filename.trim().to_string()
+/// Add the commands run since the last write to the history file at `path`,
+/// keeping what other shells wrote to it in the meantime
+///
+/// With `HISTCONTROL=erasedups`, earlier copies of a command are dropped from
+/// the merged history; `limit` keeps only that many of the newest entries.
+fn merge_history_file(path: &str, limit: Option<usize>, state: &ShellState) -> io::Result<()> {
+    let mut entries = match fs::read_to_string(path) {
+        Ok(content) => parse_history_file(&content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    entries.extend(state.history_entries(state.last_written_count..state.editor.history().len()));
+
+    if state.history_control("erasedups") {
+        let mut seen = HashSet::new();
+        let mut kept: Vec<(String, i64)> = entries
+            .into_iter()
+            .rev()
+            .filter(|(entry, _)| seen.insert(entry.clone()))
+            .collect();
+        kept.reverse();
+        entries = kept;
+    }
+    if let Some(limit) = limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+    write_history_file(path, &entries, state)
 }
 
-/// Handle specific logic for type command
-fn handle_type_logic(target: &str) {
-    if target.is_empty() {
-        return;
+/// Replace the file at `path` with the given history entries
+///
+/// The entries go to a temporary file in the same directory, which is then
+/// renamed over `path`, so a crash mid-write can't leave it truncated.
+fn write_history_file(path: &str, entries: &[(String, i64)], state: &ShellState) -> io::Result<()> {
+    // Replace the file a symlink points to, not the symlink
+    let target = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = target.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let result = File::create(&temp).and_then(|mut file| {
+        write_history(&mut file, entries, state)?;
+        file.sync_all()?;
+        // Keep the permissions of the file being replaced
+        if let Ok(metadata) = fs::metadata(&target) {
+            fs::set_permissions(&temp, metadata.permissions())?;
+        }
+        fs::rename(&temp, &target)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
     }
+    result
+}
 
-    if BUILTINS.contains(&target) {
-        println!("{} is a shell builtin", target);
-    } else if let Some(path) = find_command_in_path(target) {
-        println!("{} is {}", target, path.display());
-    } else {
-        eprintln!("{}: not found", target);
+/// Write history entries to a file, one command per line
+///
+/// With `HISTTIMEFORMAT` set, each is preceded by a `#<Unix time>` line, like
+/// bash, so the times survive a restart.
+fn write_history(file: &mut File, entries: &[(String, i64)], state: &ShellState) -> io::Result<()> {
+    let with_times = state.var("HISTTIMEFORMAT").is_some();
+    let ignore_dups = state.history_control("ignoredups");
+    let mut previous = None;
+    for (entry, time) in entries {
+        // With `ignoredups`, skip repeats (e.g. read back with `history -r`)
+        if ignore_dups && previous == Some(entry) {
+            continue;
+        }
+        previous = Some(entry);
+        if with_times {
+            writeln!(file, "#{}", time)?;
+        }
+        writeln!(file, "{}", entry)?;
     }
+    Ok(())
 }
 
-/// Parse command line arguments, correctly handle quotes, spaces and escapes
+/// Parse a history file into commands and when they ran
 ///
-/// Rules:
-/// - Inside single quotes: all characters are literals, including double quotes and backslashes
-/// - Inside double quotes: preserve spaces, single quotes treated as normal chars, \ only escapes " and \ itself
-/// - Backslash outside quotes: escape next character, make it literal, backslash itself removed
-/// - Consecutive spaces outside quotes treated as separators
-/// - Adjacent quoted strings are concatenated (when no space separates them)
-/// - Empty quotes are ignored
+/// A `#<Unix time>` line gives the time of the command after it; commands
+/// without one get the current time, like bash.
+fn parse_history_file(content: &str) -> Vec<(String, i64)> {
+    let now = unix_time();
+    let mut entries = Vec::new();
+    let mut time = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if let Some(stamp) = trimmed.strip_prefix('#')
+            && let Ok(stamp) = stamp.parse()
+        {
+            time = Some(stamp);
+        } else if !trimmed.is_empty() {
+            entries.push((trimmed.to_string(), time.take().unwrap_or(now)));
+        }
+    }
+    entries
+}
+
+/// Parse a `HISTSIZE`-style limit, falling back to the default when unset or invalid
+fn history_limit(value: Option<&str>) -> usize {
+    value
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_HISTSIZE)
+}
+
+/// Build the output of `echo`, including its trailing newline unless a
+/// leading `-n` option suppresses it
 ///
-/// Returns: token array containing command and all arguments
-fn parse_args(input: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current_arg = String::new();
-    let mut chars = input.chars().peekable();
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    let mut escaped = false; // Track if in escape state (only outside quotes)
+/// `-e` interprets backslash escapes and `-E` (the default) leaves them
+/// alone. Like bash, only leading arguments made up entirely of option
+/// letters (e.g. `-n` or `-ne`) are options; anything else, including `--`,
+/// is printed.
+fn echo_output(args: &[String]) -> String {
+    let option_count = args
+        .iter()
+        .take_while(|arg| {
+            arg.strip_prefix('-').is_some_and(|flags| {
+                !flags.is_empty() && flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E'))
+            })
+        })
+        .count();
 
-    while let Some(ch) = chars.next() {
-        if escaped {
-            // If previous character was backslash (outside quotes), current char is literal
-            current_arg.push(ch);
-            escaped = false;
-            continue;
+    let mut newline = true;
+    let mut escapes = false;
+    for flag in args[..option_count].iter().flat_map(|arg| arg[1..].chars()) {
+        match flag {
+            'n' => newline = false,
+            'e' => escapes = true,
+            _ => escapes = false,
         }
+    }
 
-        match ch {
-            '\\' if in_double_quote => {
-                // Backslash inside double quotes: only escape specific chars (", \)
-                if let Some(&next_ch) = chars.peek() {
-                    if next_ch == '"' || next_ch == '\\' {
-                        // Escape: skip backslash, add escaped character
-                        chars.next(); // Consume next character
-                        current_arg.push(next_ch);
-                    } else {
-                        // Other characters: backslash as literal
-                        current_arg.push('\\');
-                    }
-                } else {
-                    // Backslash is last character, treat as literal
-                    current_arg.push('\\');
+    let mut output = args[option_count..].join(" ");
+    if escapes {
+        let (text, stop) = interpret_escapes(&output);
+        // `\c` ends the output, including the newline
+        if stop {
+            return text;
+        }
+        output = text;
+    }
+    if newline {
+        output.push('\n');
+    }
+    output
+}
+
+/// Interpret the backslash escapes of `echo -e`: `\n`, `\t`, `\r`, `\\`,
+/// `\a`, `\b`, `\e`, `\f`, `\v`, `\0nnn` (octal), `\xHH` (hex) and `\c`
+///
+/// Returns the text and whether a `\c` cut it short. Unknown escapes are kept
+/// as typed.
+fn interpret_escapes(text: &str) -> (String, bool) {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+
+    // Read up to `max` digits in `radix` as a character code
+    let read_code = |chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize| {
+        let mut code = 0;
+        let mut digits = 0;
+        while digits < max {
+            match chars.peek().and_then(|c| c.to_digit(radix)) {
+                Some(digit) => {
+                    code = code * radix + digit;
+                    digits += 1;
+                    chars.next();
                 }
+                None => break,
             }
-            '\\' if !in_single_quote && !in_double_quote => {
-                // Backslash outside quotes: set escape flag, backslash itself not added
-                escaped = true;
-            }
-            '\'' if !in_double_quote => {
-                // When not inside double quotes, toggle single quote state
-                in_single_quote = !in_single_quote;
-            }
-            '"' if !in_single_quote => {
-                // When not inside single quotes, toggle double quote state
-                in_double_quote = !in_double_quote;
-            }
-            ' ' if !in_single_quote && !in_double_quote => {
-                // Space outside quotes: if current arg is not empty, complete current arg
-                if !current_arg.is_empty() {
-                    args.push(current_arg.clone());
-                    current_arg.clear();
+        }
+        (char::from_u32(code), digits)
+    };
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('\\') => result.push('\\'),
+            Some('a') => result.push('\x07'),
+            Some('b') => result.push('\x08'),
+            Some('e') => result.push('\x1b'),
+            Some('f') => result.push('\x0c'),
+            Some('v') => result.push('\x0b'),
+            Some('c') => return (result, true),
+            Some('0') => {
+                if let (Some(c), _) = read_code(&mut chars, 8, 3) {
+                    result.push(c);
                 }
-                // Skip consecutive spaces
             }
-            _ => {
-                // Other characters added directly to current arg (including all chars inside quotes)
-                current_arg.push(ch);
+            Some('x') => match read_code(&mut chars, 16, 2) {
+                (Some(c), digits) if digits > 0 => result.push(c),
+                _ => result.push_str("\\x"),
+            },
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
             }
+            None => result.push('\\'),
         }
     }
 
-    // Handle last argument
-    if !current_arg.is_empty() {
-        args.push(current_arg);
-    }
-
-    args
+    (result, false)
 }
 
-/// Dynamic search logic (for type command)
-fn find_command_in_path(command: &str) -> Option<PathBuf> {
-    env::var_os("PATH").and_then(|paths| {
-        env::split_paths(&paths)
-            .map(|dir| dir.join(command))
-            .find(|full_path| is_executable(full_path))
-    })
-}
+/// Build the output of `printf FORMAT [ARG...]`
+///
+/// Supports `%s`, `%b`, `%c`, `%d`/`%i`, `%u`, `%o`, `%x`/`%X` and `%%`, with
+/// `-`/`0` flags, a width and a precision, plus backslash escapes in the
+/// format. The format is reused until all arguments are consumed; missing
+/// arguments count as empty (or 0). Returns the text and whether every
+/// argument was valid.
+fn printf_output(args: &[String]) -> (String, bool) {
+    let Some(format) = args.first() else {
+        eprintln!("printf: usage: printf format [arguments]");
+        return (String::new(), false);
+    };
+    let (format, stop) = interpret_escapes(format);
+    let mut args = args[1..].iter();
+    let mut output = String::new();
+    let mut ok = true;
 
-/// Preload all external commands (for execution validation)
-fn get_all_executables() -> HashMap<String, PathBuf> {
-    let mut map = HashMap::new();
+    loop {
+        let mut consumed = false;
+        let mut chars = format.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                output.push(ch);
+                continue;
+            }
 
-    if let Some(paths) = env::var_os("PATH") {
-        for dir in env::split_paths(&paths) {
-            if let Ok(entries) = fs::read_dir(dir) {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if is_executable(&path) {
-                        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                            map.entry(name.to_string()).or_insert(path);
-                        }
-                    }
+            // Flags, width and precision, e.g. `%-8s` or `%05.2d`
+            let mut left = false;
+            let mut zero = false;
+            while let Some(&flag) = chars.peek() {
+                match flag {
+                    '-' => left = true,
+                    '0' => zero = true,
+                    '+' | ' ' | '#' => {}
+                    _ => break,
                 }
+                chars.next();
+            }
+            let mut width = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                width = width * 10 + digit as usize;
+                chars.next();
+            }
+            let mut precision = None;
+            if chars.peek() == Some(&'.') {
+                chars.next();
+                let mut digits = 0;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    digits = digits * 10 + digit as usize;
+                    chars.next();
+                }
+                precision = Some(digits);
             }
-        }
-    }
-    map
-}
 
-/// Common check: whether path exists and has execute permission
-fn is_executable(path: &Path) -> bool {
-    fs::metadata(path)
-        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
-        .unwrap_or(false)
-}
+            let conversion = chars.next();
+            if conversion == Some('%') {
+                output.push('%');
+                continue;
+            }
+            let arg = args.next().map(|arg| arg.as_str());
+            consumed |= arg.is_some();
+            let arg = arg.unwrap_or("");
 
-/// Save history to HISTFILE (if the environment variable is set)
-fn save_history_to_histfile(history: &[String]) {
-    if let Ok(histfile_path) = env::var("HISTFILE") {
-        if let Ok(mut file) = File::create(&histfile_path) {
-            for cmd in history {
-                let _ = writeln!(file, "{}", cmd);
+            let (text, numeric) = match conversion {
+                Some('s') => (arg.to_string(), false),
+                Some('b') => (interpret_escapes(arg).0, false),
+                Some('c') => (arg.chars().take(1).collect(), false),
+                Some(conv @ ('d' | 'i' | 'u' | 'o' | 'x' | 'X')) => {
+                    let number = printf_number(arg).unwrap_or_else(|| {
+                        eprintln!("printf: {}: invalid number", arg);
+                        ok = false;
+                        0
+                    });
+                    let digits = match conv {
+                        'o' => format!("{:o}", number),
+                        'x' => format!("{:x}", number),
+                        'X' => format!("{:X}", number),
+                        _ => number.to_string(),
+                    };
+                    (digits, true)
+                }
+                Some(other) => {
+                    eprintln!("printf: %{}: invalid directive", other);
+                    return (output, false);
+                }
+                None => {
+                    eprintln!("printf: %: missing format character");
+                    return (output, false);
+                }
+            };
+
+            // Precision truncates strings and zero-pads numbers
+            let text = match precision {
+                Some(p) if numeric => {
+                    let (sign, digits) = match text.strip_prefix('-') {
+                        Some(digits) => ("-", digits),
+                        None => ("", text.as_str()),
+                    };
+                    format!("{}{:0>p$}", sign, digits)
+                }
+                Some(p) => text.chars().take(p).collect(),
+                None => text,
+            };
+
+            let padding = width.saturating_sub(text.chars().count());
+            if left {
+                output.push_str(&text);
+                output.push_str(&" ".repeat(padding));
+            } else if zero && numeric && precision.is_none() {
+                // Zeros go after the sign
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", text.as_str()),
+                };
+                output.push_str(sign);
+                output.push_str(&"0".repeat(padding));
+                output.push_str(digits);
+            } else {
+                output.push_str(&" ".repeat(padding));
+                output.push_str(&text);
             }
         }
+
+        // Reuse the format for leftover arguments, as long as it consumes some
+        if stop || !consumed || args.len() == 0 {
+            return (output, ok);
+        }
+    }
+}
+
+/// Parse a `printf` numeric argument: decimal, `0x` hex, `0` octal, or `'c`
+/// for a character's code
+fn printf_number(arg: &str) -> Option<i64> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Some(0);
     }
+    if let Some(quoted) = arg.strip_prefix('\'').or_else(|| arg.strip_prefix('"')) {
+        return Some(quoted.chars().next().map_or(0, |c| c as i64));
+    }
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg.strip_prefix('+').unwrap_or(arg)),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
 }
 
-/// Check if command is a builtin command
-fn is_builtin(command: &str) -> bool {
-    matches!(command, "echo" | "type" | "pwd" | "cd" | "exit" | "history")
+/// Check if a pipeline stage runs in the forked child as a builtin
+///
+/// A subset of `BUILTINS`: the others (e.g. `printf`, `test`) run as external
+/// commands inside a pipeline. An empty command (a stage of only `NAME=value`
+/// words) also runs as one, doing nothing and exiting 0 as in bash.
+fn is_pipeline_builtin(command: &str) -> bool {
+    command.is_empty()
+        || matches!(
+            command,
+            "echo"
+                | "type"
+                | "which"
+                | "pwd"
+                | "cd"
+                | "exit"
+                | "history"
+                | "shopt"
+                | "export"
+                | "unset"
+        ) && BUILTINS.contains(&command)
 }
 
-/// Execute builtin command in child process
-fn execute_builtin_in_child(command: &str, args: &[String]) {
+/// Execute builtin command in child process, returning its exit status
+#[cfg(unix)]
+fn execute_builtin_in_child(command: &str, args: &[String], state: &ShellState) -> i32 {
     // For commands that don't use stdin (type, pwd), need to consume all stdin input
     // This avoids "Broken pipe" error from previous command when pipe is closed
     // Note: echo should not consume stdin as it only outputs arguments
-    let should_consume_stdin = matches!(command, "type" | "pwd");
+    let should_consume_stdin = matches!(command, "type" | "which" | "pwd");
 
     if should_consume_stdin {
         // Read and discard all stdin data
@@ -967,47 +6503,172 @@ fn execute_builtin_in_child(command: &str, args: &[String]) {
         }
     }
 
+    let (output, status) = builtin_output(command, args, state);
+    write_child_output(&output);
+    status
+}
+
+/// Output and exit status of a builtin run as a pipeline stage; errors go
+/// straight to stderr
+fn builtin_output(command: &str, args: &[String], state: &ShellState) -> (String, i32) {
     match command {
-        "echo" => {
-            println!("{}", args.join(" "));
-        }
-        "type" => {
-            if let Some(target) = args.first() {
-                if BUILTINS.contains(&target.as_str()) {
-                    println!("{} is a shell builtin", target);
-                } else if let Some(path) = find_command_in_path(target) {
-                    println!("{} is {}", target, path.display());
-                } else {
-                    eprintln!("{}: not found", target);
+        "echo" => (echo_output(args), 0),
+        "type" => type_output(args, env::var("PATH").ok().as_deref()),
+        // A forked stage has no cache to consult, so search PATH
+        "which" => {
+            let paths: Vec<PathBuf> = args
+                .iter()
+                .filter_map(|name| find_command_in_path(name, env::var("PATH").ok().as_deref()))
+                .collect();
+            let output = paths
+                .iter()
+                .map(|path| format!("{}\n", path.display()))
+                .collect();
+            (output, if paths.len() == args.len() { 0 } else { 1 })
+        }
+        "pwd" => match env::current_dir() {
+            Ok(dir) => (format!("{}\n", dir.display()), 0),
+            Err(_) => (String::new(), 1),
+        },
+        "history" => (
+            history_output(&state.listed_history(), HistorySelection::from_args(args)),
+            0,
+        ),
+        // Like bash, changes made in a pipeline stage don't reach the shell,
+        // so they're made to a copy
+        "export" => export_output(args, &mut state.env.clone()),
+        "shopt" => shopt_output(args, &mut state.options.clone()),
+        // Like bash, a pipeline stage can't change the shell's directory;
+        // only report a target that `cd` would refuse
+        "cd" => {
+            if let Some(target) = args
+                .first()
+                .filter(|target| !matches!(target.as_str(), "-" | "~"))
+            {
+                let path = Path::new(target);
+                if !path.is_dir() {
+                    let error_msg = if path.exists() {
+                        "Not a directory"
+                    } else {
+                        "No such file or directory"
+                    };
+                    eprintln!("cd: {}: {}", target, error_msg);
+                    return (String::new(), 1);
                 }
             }
+            (String::new(), 0)
         }
-        "pwd" => {
-            if let Ok(dir) = env::current_dir() {
-                println!("{}", dir.display());
-            }
+        _ => (String::new(), 0),
+    }
+}
+
+/// Write builtin output from a pipeline child
+///
+/// If the reader has closed the pipe (e.g. `builtin | head`), exit quietly with
+/// the status of a process killed by SIGPIPE instead of panicking in `print!`.
+#[cfg(unix)]
+fn write_child_output(text: &str) {
+    let mut stdout = io::stdout().lock();
+    if let Err(e) = write!(stdout, "{}", text).and_then(|_| stdout.flush()) {
+        let code = if e.kind() == io::ErrorKind::BrokenPipe {
+            128 + libc::SIGPIPE
+        } else {
+            1
+        };
+        std::process::exit(code);
+    }
+}
+
+/// Make the process environment match the shell's exported variables
+///
+/// Only called in a freshly forked child, which is single-threaded, so
+/// mutating the environment is sound there.
+#[cfg(unix)]
+fn apply_env_in_child(env: &HashMap<String, String>) {
+    for (name, _) in env::vars_os() {
+        if name.to_str().is_none_or(|name| !env.contains_key(name)) {
+            unsafe { env::remove_var(&name) };
         }
-        _ => {}
+    }
+    for (name, value) in env {
+        unsafe { env::set_var(name, value) };
     }
 }
 
-/// Execute pipeline command
-fn execute_pipeline(commands: Vec<(String, Vec<String>)>) -> io::Result<()> {
+/// Execute pipeline command, returning the exit status of its last command
+fn execute_pipeline(commands: Vec<PipelineStage>, state: &ShellState) -> io::Result<i32> {
     if commands.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
-    if commands.len() == 1 {
-        // Only one command, execute directly
-        let (command, args) = &commands[0];
-        if is_builtin(command) {
-            execute_builtin_in_child(command, args);
-        } else {
-            let _ = Command::new(command).args(args).status();
+    let statuses = spawn_pipeline(&commands, state)?;
+    Ok(pipeline_status(&statuses, state.options.pipefail))
+}
+
+/// Combine the exit statuses of a pipeline's stages: the last one, or with
+/// `pipefail` the rightmost failure
+fn pipeline_status(statuses: &[i32], pipefail: bool) -> i32 {
+    if pipefail {
+        statuses
+            .iter()
+            .rev()
+            .copied()
+            .find(|&status| status != 0)
+            .unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
+    }
+}
+
+/// A pipeline stage's redirections, opened by the shell for its child to apply
+#[cfg(unix)]
+#[derive(Default)]
+struct StageRedirects {
+    /// Open files, kept alive until the stage is forked
+    _files: Vec<File>,
+    /// `(source, fd)` pairs the child passes to `dup2` in order, after its
+    /// pipes are connected; so `2>&1` in a stage that writes to a pipe sends
+    /// stderr down the pipe too
+    dups: Vec<(i32, i32)>,
+}
+
+/// Open a pipeline stage's redirections in the shell
+#[cfg(unix)]
+fn open_stage_redirections(
+    redir: &Redirection,
+    options: &ShellOptions,
+) -> io::Result<StageRedirects> {
+    let mut files = Vec::new();
+    let mut dups = Vec::new();
+
+    if let Some(stdin_file) = &redir.stdin_file {
+        files.push(File::open(stdin_file).map_err(|e| path_error(stdin_file, e))?);
+        dups.push((files[files.len() - 1].as_raw_fd(), 0));
+    }
+    if let Some(body) = &redir.heredoc {
+        files.push(heredoc_pipe(body.clone())?);
+        dups.push((files[files.len() - 1].as_raw_fd(), 0));
+    }
+    for output in &redir.outputs {
+        match output {
+            OutputRedirect::File(fd, path, mode) => {
+                files.push(open_redirect_file(path, *mode, options)?);
+                dups.push((files[files.len() - 1].as_raw_fd(), *fd));
+            }
+            OutputRedirect::Dup(fd, target) => dups.push((*target, *fd)),
         }
-        return Ok(());
     }
 
+    Ok(StageRedirects {
+        _files: files,
+        dups,
+    })
+}
+
+/// Run a pipeline with forked children joined by pipes, so builtin stages can
+/// write to the pipe like external ones
+#[cfg(unix)]
+fn spawn_pipeline(commands: &[PipelineStage], state: &ShellState) -> io::Result<Vec<i32>> {
     // Create pipes and execute multiple commands
     let mut pipes: Vec<(i32, i32)> = Vec::new();
 
@@ -1024,8 +6685,17 @@ fn execute_pipeline(commands: Vec<(String, Vec<String>)>) -> io::Result<()> {
 
     let mut pids = Vec::new();
 
-    for (i, (command, args)) in commands.iter().enumerate() {
-        let is_cmd_builtin = is_builtin(command);
+    for (i, (command, args, redirection, assignments)) in commands.iter().enumerate() {
+        let is_cmd_builtin = is_pipeline_builtin(command);
+
+        // Open the stage's files before forking, so errors are reported once;
+        // a stage whose redirection fails still runs, but only to exit with 1
+        let redirects = match redirection {
+            Some(redir) => {
+                open_stage_redirections(redir, &state.options).map_err(|e| eprintln!("{}", e))
+            }
+            None => Ok(StageRedirects::default()),
+        };
 
         unsafe {
             let pid = libc::fork();
@@ -1033,12 +6703,24 @@ fn execute_pipeline(commands: Vec<(String, Vec<String>)>) -> io::Result<()> {
             if pid < 0 {
                 return Err(io::Error::last_os_error());
             } else if pid == 0 {
-                // Child process
+                // Child process: let Ctrl-C stop builtins running here too
+                libc::signal(libc::SIGINT, libc::SIG_DFL);
+                // The shell ignores SIGPIPE (as Rust programs do), and exec keeps
+                // that; restore the default so `yes | head` ends `yes` quietly
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
 
                 // Setup stdin: if not first command, read from previous pipe
                 if i > 0 {
                     let (read_fd, _) = pipes[i - 1];
                     libc::dup2(read_fd, 0);
+                } else if state.stdin_is_script {
+                    // Don't let the first command read (or hang on) the
+                    // shell's own input
+                    let null_fd = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+                    if null_fd >= 0 {
+                        libc::dup2(null_fd, 0);
+                        libc::close(null_fd);
+                    }
                 }
 
                 // Setup stdout: if not last command, write to next pipe
@@ -1053,26 +6735,43 @@ fn execute_pipeline(commands: Vec<(String, Vec<String>)>) -> io::Result<()> {
                     libc::close(*write_fd);
                 }
 
+                // Apply the stage's own redirections on top of the pipes
+                let Ok(redirects) = &redirects else {
+                    std::process::exit(1);
+                };
+                for &(source, fd) in &redirects.dups {
+                    libc::dup2(source, fd);
+                }
+
+                // Give the child the shell's exported variables,
+                // plus the stage's own `NAME=value` ones
+                let mut env = state.env.clone();
+                env.extend(assignments.iter().cloned());
+                apply_env_in_child(&env);
+
                 if is_cmd_builtin {
                     // Execute builtin command
-                    execute_builtin_in_child(command, args);
-                    std::process::exit(0);
+                    let status = execute_builtin_in_child(command, args, state);
+                    std::process::exit(status);
                 } else {
                     // Execute external command
-                    let cmd_cstring = std::ffi::CString::new(command.as_str()).unwrap();
-                    let mut args_cstring: Vec<std::ffi::CString> = vec![cmd_cstring.clone()];
-                    args_cstring.extend(
-                        args.iter()
-                            .map(|a| std::ffi::CString::new(a.as_str()).unwrap()),
-                    );
+                    let args_cstring: Result<Vec<std::ffi::CString>, _> = std::iter::once(command)
+                        .chain(args)
+                        .map(|a| std::ffi::CString::new(a.as_str()))
+                        .collect();
+                    // exec can't pass a string containing a null byte
+                    let Ok(args_cstring) = args_cstring else {
+                        eprintln!("{}: argument contains null byte", command);
+                        std::process::exit(126);
+                    };
                     let mut args_ptr: Vec<*const libc::c_char> =
                         args_cstring.iter().map(|s| s.as_ptr()).collect();
                     args_ptr.push(std::ptr::null());
 
-                    libc::execvp(cmd_cstring.as_ptr(), args_ptr.as_ptr());
+                    libc::execvp(args_ptr[0], args_ptr.as_ptr());
                     // If execvp returns, an error occurred
-                    eprintln!("{}: command not found", command);
-                    std::process::exit(127);
+                    let status = stage_spawn_error_status(command, io::Error::last_os_error());
+                    std::process::exit(status);
                 }
             } else {
                 // Parent process, record child process PID
@@ -1089,95 +6788,526 @@ fn execute_pipeline(commands: Vec<(String, Vec<String>)>) -> io::Result<()> {
         }
     }
 
-    // Wait for all child processes to complete
+    // Wait for all child processes to complete, collecting their statuses
+    let mut statuses = Vec::new();
     for pid in pids {
+        let mut status = 0;
         unsafe {
-            let mut status = 0;
             libc::waitpid(pid, &mut status, 0);
         }
+        statuses.push(wait_status_code(status));
     }
 
-    Ok(())
+    Ok(statuses)
 }
 
-fn generate_command_with_ai(prompts: Vec<String>) {
-    let prompt_text = prompts.join(" ");
-    
-    if prompt_text.trim().is_empty() {
-        eprintln!("AI: Please provide a description of what you want to do");
-        return;
+/// Run a pipeline of two or more commands where `fork` isn't available
+///
+/// External stages are spawned with their stdout piped to the next stage's
+/// stdin. A builtin stage runs in the shell: its output is fed to the next
+/// stage (or printed, if it's last) and its input is discarded.
+#[cfg(not(unix))]
+fn spawn_pipeline(commands: &[PipelineStage], state: &ShellState) -> io::Result<Vec<i32>> {
+    let last = commands.len() - 1;
+    let mut stdin: Option<Stdio> = None;
+    let mut pending_input: Option<String> = None;
+    let mut children = Vec::new();
+    // Builtin stages set their status as they run; external ones are filled in once they exit
+    let mut statuses = vec![0; commands.len()];
+
+    for (i, (command, args, redirection, assignments)) in commands.iter().enumerate() {
+        let files = match open_redirections(redirection, &state.options) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("{}", e);
+                pending_input = None;
+                stdin = None;
+                statuses[i] = 1;
+                continue;
+            }
+        };
+
+        if is_pipeline_builtin(command) {
+            let (output, status) = builtin_output(command, args, state);
+            statuses[i] = status;
+            if i == last {
+                print!("{}", output);
+                let _ = io::stdout().flush();
+            } else {
+                pending_input = Some(output);
+                stdin = Some(Stdio::piped());
+            }
+            continue;
+        }
+
+        let mut child_command = Command::new(command);
+        child_command
+            .args(args)
+            .env_clear()
+            .envs(&state.env)
+            .envs(assignments.iter().map(|(name, value)| (name, value)));
+        if let Some(stdin) = stdin.take() {
+            child_command.stdin(stdin);
+        } else if i == 0 && state.stdin_is_script {
+            // Don't let the first command read the shell's own input
+            child_command.stdin(Stdio::null());
+        }
+        if i < last {
+            child_command.stdout(Stdio::piped());
+        }
+        // The stage's own redirections take precedence over the pipes
+        if let Some(file) = files.stdin {
+            pending_input = None;
+            child_command.stdin(file);
+        }
+        if let Some(file) = files.stdout {
+            child_command.stdout(file);
+        }
+        if let Some(file) = files.stderr {
+            child_command.stderr(file);
+        }
+
+        match child_command.spawn() {
+            Ok(mut child) => {
+                // Feed a builtin's output from a thread so a full pipe can't block the shell
+                if let (Some(input), Some(mut child_stdin)) =
+                    (pending_input.take(), child.stdin.take())
+                {
+                    std::thread::spawn(move || {
+                        let _ = child_stdin.write_all(input.as_bytes());
+                    });
+                }
+                stdin = child.stdout.take().map(Stdio::from);
+                children.push((i, child));
+            }
+            Err(e) => {
+                pending_input = None;
+                statuses[i] = stage_spawn_error_status(command, e);
+            }
+        }
     }
 
-    // Create tokio runtime to run async code
-    let runtime = match tokio::runtime::Runtime::new() {
-        Ok(rt) => rt,
-        Err(e) => {
-            eprintln!("AI: Failed to create async runtime: {}", e);
-            return;
+    // Wait for every stage, collecting their statuses
+    for (i, mut child) in children {
+        statuses[i] = child.wait().map(exit_status_code).unwrap_or(1);
+    }
+
+    Ok(statuses)
+}
+
+/// Convert a raw `waitpid` status to a shell status code (128+N when killed by signal N)
+#[cfg(unix)]
+fn wait_status_code(status: i32) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+/// AI service that answers `!` requests, chosen with `SHELL_AI_PROVIDER`
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum AiProvider {
+    OpenAi,
+    Anthropic,
+    /// A local Ollama server, which needs no API key
+    Ollama,
+}
+
+impl AiProvider {
+    /// Read `SHELL_AI_PROVIDER` (default: `openai`)
+    fn from_env(env: &HashMap<String, String>) -> Result<Self, String> {
+        let name = env
+            .get("SHELL_AI_PROVIDER")
+            .map(|p| p.trim().to_lowercase())
+            .unwrap_or_default();
+        match name.as_str() {
+            "" | "openai" => Ok(AiProvider::OpenAi),
+            "anthropic" => Ok(AiProvider::Anthropic),
+            "ollama" => Ok(AiProvider::Ollama),
+            _ => Err(format!(
+                "unknown SHELL_AI_PROVIDER `{}` (expected openai, anthropic or ollama)",
+                name
+            )),
         }
-    };
+    }
+
+    /// Model used when `SHELL_AI_MODEL` isn't set
+    fn default_model(&self) -> &'static str {
+        match self {
+            AiProvider::OpenAi => openai::GPT_4O,
+            AiProvider::Anthropic => anthropic::CLAUDE_3_5_SONNET,
+            AiProvider::Ollama => "llama3.2",
+        }
+    }
+
+    /// Name of the environment variable holding the API key, if one is needed
+    fn api_key_var(&self) -> Option<&'static str> {
+        match self {
+            AiProvider::OpenAi => Some("OPENAI_API_KEY"),
+            AiProvider::Anthropic => Some("ANTHROPIC_API_KEY"),
+            AiProvider::Ollama => None,
+        }
+    }
+
+    /// Resolve what an agent with `preamble` as its instructions is built from
+    fn settings(
+        &self,
+        preamble: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<AgentSettings, String> {
+        let api_key = match self.api_key_var() {
+            Some(key_var) => match env.get(key_var) {
+                Some(api_key) => api_key.clone(),
+                None => return Err(format!("{} environment variable not set", key_var)),
+            },
+            None => String::new(),
+        };
+
+        // The model name is passed through to the API as is
+        let model = env
+            .get("SHELL_AI_MODEL")
+            .map(|model| model.trim())
+            .filter(|model| !model.is_empty())
+            .unwrap_or(self.default_model())
+            .to_string();
+
+        let url = match self {
+            AiProvider::Ollama => env
+                .get("SHELL_AI_OLLAMA_URL")
+                .map(|url| url.as_str())
+                .unwrap_or("http://localhost:11434")
+                .to_string(),
+            _ => String::new(),
+        };
+
+        Ok(AgentSettings {
+            provider: self.clone(),
+            api_key,
+            model,
+            url,
+            preamble: preamble.to_string(),
+        })
+    }
+}
+
+/// Everything an AI agent is built from; a cached agent is reused while
+/// these stay the same
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct AgentSettings {
+    provider: AiProvider,
+    api_key: String,
+    model: String,
+    /// Server address (Ollama only)
+    url: String,
+    preamble: String,
+}
+
+impl AgentSettings {
+    /// Build the client and agent
+    fn build(&self) -> AiAgent {
+        match self.provider {
+            AiProvider::OpenAi => AiAgent::OpenAi(
+                openai::Client::new(&self.api_key)
+                    .agent(&self.model)
+                    .preamble(&self.preamble)
+                    .build(),
+            ),
+            // Anthropic requires an explicit output limit
+            AiProvider::Anthropic => AiAgent::Anthropic(
+                anthropic::Client::new(&self.api_key)
+                    .agent(&self.model)
+                    .preamble(&self.preamble)
+                    .max_tokens(1024)
+                    .build(),
+            ),
+            AiProvider::Ollama => AiAgent::Ollama(
+                ollama::Client::from_url(&self.url)
+                    .agent(&self.model)
+                    .preamble(&self.preamble)
+                    .build(),
+            ),
+        }
+    }
+}
+
+/// A ready-to-use agent for one of the providers
+enum AiAgent {
+    OpenAi(Agent<openai::CompletionModel>),
+    Anthropic(Agent<anthropic::CompletionModel>),
+    Ollama(Agent<ollama::CompletionModel>),
+}
+
+impl AiAgent {
+    /// Send `prompt` with earlier `(request, reply)` pairs as chat history,
+    /// passing the reply to `on_chunk` as it streams in, and return the
+    /// complete reply
+    async fn prompt(
+        &self,
+        prompt: &str,
+        history: &[(String, String)],
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, String> {
+        let history: Vec<Message> = history
+            .iter()
+            .flat_map(|(request, reply)| [Message::user(request), Message::assistant(reply)])
+            .collect();
+
+        let stream = match self {
+            AiAgent::OpenAi(agent) => agent.stream_chat(prompt, history).await,
+            AiAgent::Anthropic(agent) => agent.stream_chat(prompt, history).await,
+            AiAgent::Ollama(agent) => agent.stream_chat(prompt, history).await,
+        };
+
+        collect_stream(stream, on_chunk).await
+    }
+}
+
+/// The async runtime and agents behind `!` requests, kept for the whole
+/// session so repeated requests don't pay for setting them up again
+#[derive(Default)]
+struct AiSession {
+    /// Created on the first request
+    runtime: Option<tokio::runtime::Runtime>,
+    agents: HashMap<AgentSettings, AiAgent>,
+}
+
+impl AiSession {
+    /// Send `prompt` to the model with `preamble` as its instructions and
+    /// earlier `(request, reply)` pairs as chat history, passing the reply to
+    /// `on_chunk` as it streams in, and return the complete reply
+    fn prompt(
+        &mut self,
+        preamble: &str,
+        prompt: &str,
+        history: &[(String, String)],
+        env: &HashMap<String, String>,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> Result<String, String> {
+        let settings = AiProvider::from_env(env)?.settings(preamble, env)?;
+
+        if self.runtime.is_none() {
+            // `block_on` drives the requests on this thread, so no worker
+            // threads are left running into the children the shell forks
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| format!("Failed to create async runtime: {}", e))?;
+            self.runtime = Some(runtime);
+        }
+        let runtime = self.runtime.as_ref().expect("runtime was just created");
+
+        // Settings that changed (e.g. a new SHELL_AI_MODEL) get a new agent
+        let agent = self
+            .agents
+            .entry(settings)
+            .or_insert_with_key(AgentSettings::build);
+
+        runtime.block_on(agent.prompt(prompt, history, on_chunk))
+    }
+}
+
+/// Pass each text chunk of a streamed AI reply to `on_chunk`, returning the
+/// whole reply
+async fn collect_stream(
+    stream: Result<StreamingResult, CompletionError>,
+    on_chunk: &mut dyn FnMut(&str),
+) -> Result<String, String> {
+    let mut stream = stream.map_err(|e| format!("AI request failed: {}", e))?;
+    let mut response = String::new();
 
-    // Call AI in async environment
-    match runtime.block_on(async {
-        // Check environment variable
-        if env::var("OPENAI_API_KEY").is_err() {
-            return Err("OPENAI_API_KEY environment variable not set".to_string());
+    while let Some(choice) = stream.next().await {
+        match choice.map_err(|e| format!("AI request failed: {}", e))? {
+            StreamingChoice::Message(text) => {
+                on_chunk(&text);
+                response.push_str(&text);
+            }
+            // No tools are registered with the agent
+            StreamingChoice::ToolCall(..) => {}
         }
+    }
+
+    Ok(response)
+}
+
+/// Ask the AI what a command does and print its explanation as it streams in
+fn explain_command_with_ai(
+    command: Vec<String>,
+    env: &HashMap<String, String>,
+    ai: &mut AiSession,
+) {
+    let command = command.join(" ");
+
+    if command.trim().is_empty() {
+        eprintln!("AI: Please provide a command to explain");
+        return;
+    }
+
+    let result = ai.prompt(
+        "You are a helpful shell command assistant. \
+         Given a shell command, explain concisely what it does, \
+         covering each part of a pipeline and any notable options. \
+         Answer in plain text without markdown formatting.",
+        &format!("Explain this command:\n{}", command),
+        &[],
+        env,
+        &mut |chunk| {
+            print!("{}", chunk);
+            let _ = io::stdout().flush();
+        },
+    );
+
+    match result {
+        // End the streamed explanation's last line
+        Ok(explanation) if !explanation.ends_with('\n') => println!(),
+        Ok(_) => {}
+        Err(e) => eprintln!("AI: {}", e),
+    }
+}
+
+/// Earlier exchanges kept as context while refining an AI suggestion
+const AI_CONVERSATION_LIMIT: usize = 10;
+
+/// Ask the AI for a command and run it once confirmed
+///
+/// With a `pipeline` (from `!prompt | rest`), the generated command's output is
+/// piped into it: the model is told, and the suggestion is shown and run as
+/// `command | rest`.
+///
+/// Answering `e` instead returns the suggestion, for the next prompt to be
+/// pre-filled with it, and `r` asks for a follow-up request that is sent
+/// with the `conversation` so far.
+fn generate_command_with_ai(
+    prompts: Vec<String>,
+    pipeline: Option<String>,
+    env: &HashMap<String, String>,
+    ai: &mut AiSession,
+    conversation: &mut Vec<(String, String)>,
+) -> Option<String> {
+    let mut prompt_text = prompts.join(" ");
 
-        // Create OpenAI client
-        let client = openai::Client::from_env();
+    if prompt_text.trim().is_empty() {
+        eprintln!("AI: Please provide a description of what you want to do");
+        return None;
+    }
+
+    // Each refinement (`r`) asks again with the earlier exchanges as context
+    loop {
+        // Whether any of the reply has been shown yet
+        let mut streamed = false;
+
+        // The first request gives the task in full; follow-ups are sent as typed
+        let full_prompt = if conversation.is_empty() {
+            // Get current working directory as context
+            let cwd = env::current_dir()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
 
-        // Create agent specifically for generating shell commands
-        let agent = client
-            .agent(openai::GPT_4O)
-            .preamble(
-                "You are a helpful shell command assistant. \
-                 Given a natural language description, generate the appropriate shell command. \
-                 Return ONLY the command itself without any explanation, markdown formatting, or code blocks. \
-                 The command should be ready to execute directly in a bash/zsh shell."
+            let piped_into = match &pipeline {
+                Some(rest) => format!("\nIts output will be piped into: {}", rest),
+                None => String::new(),
+            };
+            format!(
+                "Current directory: {}\nTask: {}{}\nGenerate the shell command:",
+                cwd, prompt_text, piped_into
             )
-            .build();
+        } else {
+            prompt_text.clone()
+        };
 
-        // Get current working directory as context
-        let cwd = env::current_dir()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| "unknown".to_string());
+        // Send request to AI, with a preamble specifically for generating shell commands
+        match ai.prompt(
+            "You are a helpful shell command assistant. \
+             Given a natural language description, generate the appropriate shell command. \
+             Return ONLY the command itself without any explanation, markdown formatting, or code blocks. \
+             The command should be ready to execute directly in a bash/zsh shell.",
+            &full_prompt,
+            conversation,
+            env,
+            // Show the command as it's generated
+            &mut |chunk| {
+                if !streamed {
+                    println!("AI suggested command:");
+                    print!("$ ");
+                    streamed = true;
+                }
+                print!("{}", chunk);
+                let _ = io::stdout().flush();
+            },
+        ) {
+            Ok(reply) => {
+                let command = match &pipeline {
+                    Some(rest) => format!("{} | {}", reply.trim(), rest),
+                    None => reply.trim().to_string(),
+                };
 
-        // Build complete prompt
-        let full_prompt = format!(
-            "Current directory: {}\nTask: {}\nGenerate the shell command:",
-            cwd, prompt_text
-        );
+                // Finish the streamed preview line before asking anything
+                if streamed {
+                    match &pipeline {
+                        Some(rest) => println!(" | {}", rest),
+                        None => println!(),
+                    }
+                } else {
+                    println!("AI suggested command:");
+                    println!("$ {}", command);
+                }
+                println!();
+
+                // Destructive commands need the full word `yes`, and never run unattended
+                let danger = dangerous_pattern(&command, env);
+                if let Some(pattern) = &danger {
+                    eprintln!(
+                        "\x1b[1;31mWarning: this command looks destructive (matches `{}`)\x1b[0m",
+                        pattern
+                    );
+                }
+
+                // Read user confirmation, but never block on a non-terminal stdin:
+                // there, only SHELL_AI_AUTORUN=true runs the suggestion
+                let interactive = io::stdin().is_terminal();
+                let answer = if !interactive {
+                    String::new()
+                } else if danger.is_some() {
+                    read_answer("Type `yes` to execute this command (or `e` to edit, `r` to refine it): ")
+                } else {
+                    read_answer("Execute this command? (y/n/e/r): ").to_lowercase()
+                };
+                if interactive && answer == "r" {
+                    let follow_up = read_answer("Refine: ");
+                    if !follow_up.is_empty() {
+                        conversation.push((full_prompt, reply));
+                        // Keep only the most recent exchanges
+                        let excess = conversation.len().saturating_sub(AI_CONVERSATION_LIMIT);
+                        conversation.drain(..excess);
+                        prompt_text = follow_up;
+                        continue;
+                    }
+                }
+                // Anything else ends the refinement session
+                conversation.clear();
+                if interactive && answer == "e" {
+                    return Some(command);
+                }
+                let execute = if !interactive {
+                    danger.is_none() && env.get("SHELL_AI_AUTORUN").is_some_and(|v| v == "true")
+                } else if danger.is_some() {
+                    answer == "yes"
+                } else {
+                    answer == "y" || answer == "yes"
+                };
 
-        // Send request to AI
-        let response = agent.prompt(&full_prompt).await
-            .map_err(|e| format!("AI request failed: {}", e))?;
-
-        Ok(response)
-    }) {
-        Ok(command) => {
-            let command = command.trim();
-            
-            // Display AI generated command
-            println!("AI suggested command:");
-            println!("$ {}", command);
-            println!();
-            print!("Execute this command? (y/n): ");
-            io::stdout().flush().unwrap();
-
-            // Read user confirmation
-            let stdin = io::stdin();
-            let mut response = String::new();
-            if stdin.lock().read_line(&mut response).is_ok() {
-                let response = response.trim().to_lowercase();
-                if response == "y" || response == "yes" {
+                if execute {
                     println!("Executing...");
                     // Use sh -c to execute command, supporting pipes, redirects and other complex commands
                     let status = Command::new("sh")
                         .arg("-c")
-                        .arg(command)
+                        .arg(&command)
+                        .env_clear()
+                        .envs(env)
                         .status();
-                    
+
                     match status {
                         Ok(exit_status) => {
                             if !exit_status.success() {
@@ -1188,13 +7318,66 @@ fn generate_command_with_ai(prompts: Vec<String>) {
                             eprintln!("Failed to execute command: {}", e);
                         }
                     }
-                } else {
+                } else if interactive {
                     println!("Command cancelled.");
+                } else if danger.is_some() {
+                    println!("Not executed: destructive commands are only run after confirmation.");
+                } else {
+                    println!("Not executed: stdin is not a terminal (set SHELL_AI_AUTORUN=true to run it).");
                 }
             }
+            Err(e) => {
+                conversation.clear();
+                eprintln!("AI: {}", e);
+            }
         }
-        Err(e) => {
-            eprintln!("AI: {}", e);
-        }
+        break None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn history_range_in_bounds() {
+        let selection = HistorySelection::from_args(&args(&["5", "10"]));
+        assert_eq!(selection.bounds(20), (4, 10));
+    }
+
+    #[test]
+    fn history_range_reversed_is_empty() {
+        let (start, end) = HistorySelection::from_args(&args(&["10", "5"])).bounds(20);
+        assert_eq!(start, end);
+    }
+
+    #[test]
+    fn history_range_past_the_end_is_clamped() {
+        assert_eq!(HistorySelection::Range(5, 100).bounds(8), (4, 8));
+        assert_eq!(HistorySelection::Range(50, 100).bounds(8), (8, 8));
+        assert_eq!(HistorySelection::Range(0, 3).bounds(8), (0, 3));
+    }
+
+    #[test]
+    fn history_last_n() {
+        assert_eq!(HistorySelection::from_args(&args(&["3"])).bounds(8), (5, 8));
+        assert_eq!(HistorySelection::Last(100).bounds(8), (0, 8));
+        assert_eq!(HistorySelection::from_args(&[]).bounds(8), (0, 8));
+    }
+
+    #[test]
+    fn tilde_completes_under_the_given_home() {
+        let home = env::temp_dir().join(format!("shell-ai-home-{}", std::process::id()));
+        fs::create_dir_all(home.join("projects")).unwrap();
+
+        let candidates = complete_path("~/pro", true, home.to_str());
+        let replacements: Vec<&str> = candidates.iter().map(|c| c.replacement.as_str()).collect();
+        assert_eq!(replacements, ["~/projects/"]);
+
+        let _ = fs::remove_dir_all(&home);
     }
 }
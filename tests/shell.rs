@@ -0,0 +1,300 @@
+//! Run the shell on scripts fed to its stdin and check what they do
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A fresh, empty directory for one test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("shell-ai-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create test directory");
+    dir
+}
+
+/// Run `script` in `dir`, away from the user's config and history
+fn run_shell(dir: &Path, script: &str, env: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_shell-ai"))
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env_remove("HISTFILE")
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("start the shell");
+    child
+        .stdin
+        .take()
+        .expect("shell stdin")
+        .write_all(script.as_bytes())
+        .expect("write the script");
+    child.wait_with_output().expect("wait for the shell")
+}
+
+#[test]
+fn cd_into_quoted_dir_with_space() {
+    let dir = test_dir("cd-quoted");
+    fs::create_dir(dir.join("dir with space")).unwrap();
+
+    let output = run_shell(&dir, "cd \"dir with space\"\npwd\n", &[]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.trim_end().ends_with("/dir with space"),
+        "unexpected output: {:?}",
+        stdout
+    );
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn redirects_stdin_stdout_and_stderr_at_once() {
+    let dir = test_dir("redirects");
+    fs::write(dir.join("in.txt"), "from stdin\n").unwrap();
+
+    let output = run_shell(&dir, "cat - missing > out.log 2> err.log < in.txt\n", &[]);
+
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        fs::read_to_string(dir.join("out.log")).unwrap(),
+        "from stdin\n"
+    );
+    let errors = fs::read_to_string(dir.join("err.log")).unwrap();
+    assert!(
+        errors.contains("missing"),
+        "stderr went elsewhere: {:?}",
+        errors
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn builtin_into_closed_pipe_ends_quietly() {
+    let dir = test_dir("echo-head");
+    // More than a pipe holds, so `echo` is still writing when `head` exits
+    let script = format!("echo {} | head -c 1\n", "x".repeat(1 << 20));
+
+    let output = run_shell(&dir, &script, &[]);
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "x");
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn history_into_head_ends_quietly() {
+    let dir = test_dir("history-head");
+    // Enough history to fill the pipe, so `history` is still writing when `head` exits
+    let mut script = String::new();
+    for i in 0..5000 {
+        script.push_str(&format!("echo history_to_fill_a_pipe_{} > /dev/null\n", i));
+    }
+    script.push_str("history | head -n 1\n");
+
+    let output = run_shell(&dir, &script, &[("HISTSIZE", "10000")]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1, "unexpected output: {:?}", stdout);
+    assert!(lines[0].trim_start().starts_with("1 "));
+    assert!(lines[0].ends_with("echo history_to_fill_a_pipe_0 > /dev/null"));
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cd_into_substituted_dir_with_space() {
+    let dir = test_dir("cd-substitution");
+    fs::create_dir(dir.join("dir with space")).unwrap();
+
+    let output = run_shell(
+        &dir,
+        "cd \"$(printf '%s\\n' 'dir with space')\"\npwd\n",
+        &[],
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.trim_end().ends_with("/dir with space"),
+        "unexpected output: {:?}",
+        stdout
+    );
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn escaped_backtick_in_double_quotes_is_literal() {
+    let dir = test_dir("escaped-backtick");
+
+    let output = run_shell(&dir, "echo \"a\\`b\" \"x`echo y`z\"\n", &[]);
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "a`b xyz\n");
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_builtin_sees_empty_strings() {
+    let dir = test_dir("test-empty");
+
+    let script = r#"[ -n "" ] && echo WRONG
+[ -z "" ] && echo z-empty
+[ -z "$unset" ] && echo z-unset
+x=set
+[ "$x" = "" ] || echo not-equal
+x=
+[ "$x" = "" ] && echo equal
+"#;
+    let output = run_shell(&dir, script, &[]);
+
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "z-empty\nz-unset\nnot-equal\nequal\n"
+    );
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn double_quotes_escape_dollar_and_keep_empty_variables() {
+    let dir = test_dir("double-quotes");
+
+    let script = r#"x=value
+echo "\$x $x"
+printf "%s|\n" "$empty" b
+"#;
+    let output = run_shell(&dir, script, &[]);
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "$x value\n|\nb|\n");
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn assignment_only_pipeline_stage_does_nothing() {
+    let dir = test_dir("assignment-stage");
+
+    let script = "X=1 | echo first\necho $?\necho dropped | X=1 | cat\necho $?\n";
+    let output = run_shell(&dir, script, &[]);
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "first\n0\n0\n");
+    assert!(
+        output.stderr.is_empty(),
+        "unexpected errors: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn history_delete_keeps_times_with_their_entries() {
+    let dir = test_dir("history-delete");
+    fs::write(
+        dir.join("histfile"),
+        "#100\nfirst\n#200\nsecond\n#300\nthird\n",
+    )
+    .unwrap();
+
+    let script = "history -d 2\nHISTTIMEFORMAT='%s '\nhistory\nhistory -w written\n";
+    let histfile = dir.join("histfile");
+    let output = run_shell(&dir, script, &[("HISTFILE", histfile.to_str().unwrap())]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().map(str::trim_start).collect();
+    assert_eq!(lines[..2], ["1  100 first", "2  300 third"], "{:?}", stdout);
+    let written = fs::read_to_string(dir.join("written")).unwrap();
+    assert!(
+        written.starts_with("#100\nfirst\n#300\nthird\n"),
+        "unexpected file: {:?}",
+        written
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn builtin_listings_follow_redirections() {
+    let dir = test_dir("listing-redirect");
+
+    let output = run_shell(
+        &dir,
+        "export > vars.txt\nshopt > shopt.txt\nset -o > set.txt\n",
+        &[("SHELL_AI_TEST_VAR", "listed")],
+    );
+
+    assert!(output.stdout.is_empty(), "{:?}", output.stdout);
+    let vars = fs::read_to_string(dir.join("vars.txt")).unwrap();
+    assert!(vars.contains("SHELL_AI_TEST_VAR=listed\n"), "{:?}", vars);
+    let shopt = fs::read_to_string(dir.join("shopt.txt")).unwrap();
+    assert!(shopt.contains("pager\toff\n"), "{:?}", shopt);
+    let set = fs::read_to_string(dir.join("set.txt")).unwrap();
+    assert!(set.contains("errexit"), "{:?}", set);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn umask_follows_redirection() {
+    let dir = test_dir("umask-redirect");
+
+    let output = run_shell(&dir, "umask 027\numask > mask.txt\n", &[]);
+
+    assert!(output.stdout.is_empty(), "{:?}", output.stdout);
+    assert_eq!(fs::read_to_string(dir.join("mask.txt")).unwrap(), "0027\n");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn confirm_overwrite_without_terminal_refuses() {
+    let dir = test_dir("confirm-no-tty");
+    fs::write(dir.join("f.txt"), "old\n").unwrap();
+
+    let output = run_shell(
+        &dir,
+        "shopt -s confirm_overwrite\necho new > f.txt\necho $?\n",
+        &[],
+    );
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("cannot overwrite existing file"),
+        "{}",
+        stderr
+    );
+    assert_eq!(fs::read_to_string(dir.join("f.txt")).unwrap(), "old\n");
+    let _ = fs::remove_dir_all(&dir);
+}